@@ -3,21 +3,75 @@ use ic_cdk::management_canister::{
     HttpRequestArgs,
     HttpHeader,
     HttpMethod,
+    ecdsa_public_key,
+    sign_with_ecdsa,
+    EcdsaCurve,
+    EcdsaKeyId,
+    EcdsaPublicKeyArgs,
+    SignWithEcdsaArgs,
 };
 
 use ic_cdk::api::management_canister::http_request::HttpResponse; // خاص بالـ response
 use num_bigint::BigUint;
 use serde_json::json;
 
-use candid::{CandidType, Deserialize};
+use candid::{CandidType, Deserialize, Principal};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
 use std::borrow::Cow;
 use std::cell::RefCell;
+use std::thread::LocalKey;
 use serde::Serialize;
-use ic_cdk::api::time;
+use serde::de::DeserializeOwned;
+use ic_cdk::api::{msg_caller, time};
+use sha2::{Digest, Sha256};
+use ic_certification::{AsHashTree, Hash, RbTree};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
+/// Secondary index of `(key, id)` pairs, e.g. (owner id, device id).
+type IdIndex = StableBTreeMap<(u64, u64), (), Memory>;
+
+/// --------- VERSIONED STORAGE ----------
+/// Every stored value is wrapped in an envelope that records the schema
+/// version it was written with, so a struct can gain fields without breaking
+/// the records already in stable memory.
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    version: u16,
+    #[serde(with = "serde_bytes")]
+    payload: Vec<u8>,
+}
+
+trait Versioned: Serialize + DeserializeOwned {
+    /// Version written by the current code; bump it whenever the layout changes.
+    const VERSION: u16;
+
+    /// Decodes a payload written by an older `VERSION` of this type.
+    fn upgrade(version: u16, _payload: &[u8]) -> Result<Self, String> {
+        Err(format!("no upgrade path from version {}", version))
+    }
+}
+
+fn encode_versioned<T: Versioned>(value: &T) -> Vec<u8> {
+    let payload = bincode::serialize(value).unwrap();
+    bincode::serialize(&Envelope { version: T::VERSION, payload }).unwrap()
+}
+
+fn decode_versioned<T: Versioned>(bytes: &[u8]) -> T {
+    let decoded = bincode::deserialize::<Envelope>(bytes)
+        .map_err(|e| e.to_string())
+        .and_then(|envelope| {
+            if envelope.version == T::VERSION {
+                bincode::deserialize(&envelope.payload).map_err(|e| e.to_string())
+            } else {
+                T::upgrade(envelope.version, &envelope.payload)
+            }
+        });
+    match decoded {
+        Ok(value) => value,
+        Err(e) => ic_cdk::trap(format!("Failed to decode stored {}: {}", std::any::type_name::<T>(), e)),
+    }
+}
 
 /// --------- USER STRUCT & ROLE ----------
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
@@ -25,240 +79,2618 @@ pub enum Role {
     Seller,
     Specialist,
     Buyer,
+    Admin,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct RoleGrant {
+    role: Role,
+    granted_by: Principal,
+    granted_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct User {
+    id: u64,
+    principal: Principal,
+    name: String,
+    email: String,
+    roles: Vec<RoleGrant>,
+    suspended: bool,
+}
+
+impl User {
+    fn has_role(&self, role: &Role) -> bool {
+        self.roles.iter().any(|g| &g.role == role)
+    }
+
+    /// Adds `role` unless the user already holds it.
+    fn grant(&mut self, role: Role, granted_by: Principal) {
+        if !self.has_role(&role) {
+            self.roles.push(RoleGrant { role, granted_by, granted_at: time() });
+        }
+    }
+
+    /// Builds a user from a layout that only had a single, self-declared role.
+    fn from_single_role(id: u64, principal: Principal, name: String, email: String, role: Role, suspended: bool) -> User {
+        let grant = RoleGrant { role, granted_by: principal, granted_at: time() };
+        User { id, principal, name, email, roles: vec![grant], suspended }
+    }
+}
+
+/// `User` as stored before suspension was tracked (version 1).
+#[derive(Deserialize)]
+struct UserV1 {
+    id: u64,
+    principal: Principal,
+    name: String,
+    email: String,
+    role: Role,
+}
+
+/// `User` as stored with a single role (version 2).
+#[derive(Deserialize)]
+struct UserV2 {
+    id: u64,
+    principal: Principal,
+    name: String,
+    email: String,
+    role: Role,
+    suspended: bool,
+}
+
+impl Storable for User {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(encode_versioned(self)) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { decode_versioned(&bytes) }
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Versioned for User {
+    const VERSION: u16 = 3;
+
+    fn upgrade(version: u16, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            1 => {
+                let u: UserV1 = bincode::deserialize(payload).map_err(|e| e.to_string())?;
+                Ok(User::from_single_role(u.id, u.principal, u.name, u.email, u.role, false))
+            },
+            2 => {
+                let u: UserV2 = bincode::deserialize(payload).map_err(|e| e.to_string())?;
+                Ok(User::from_single_role(u.id, u.principal, u.name, u.email, u.role, u.suspended))
+            },
+            _ => Err(format!("no upgrade path from version {}", version)),
+        }
+    }
+}
+
+/// --------- DEVICE STRUCT ----------
+/// The typed sections mirror the inspection JSON in `data/devices*.json`, so
+/// an inspection record deserializes straight into them.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum DeviceCategory {
+    Laptop,
+    Mobile,
+    Tablet,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct DeviceIdentity {
+    serial_number: String,
+    #[serde(default)]
+    imei: Option<String>,
+    #[serde(default)]
+    motherboard_id: Option<String>,
+    #[serde(default)]
+    os_license: Option<String>,
+    #[serde(default)]
+    icloud_frp_lock: bool,
+    #[serde(default)]
+    carrier_lock: bool,
+    #[serde(default)]
+    bios_lock: bool,
+    #[serde(default)]
+    tamper_flags: Vec<String>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct CpuInfo {
+    model: String,
+    cores: u32,
+    #[serde(default)]
+    threads: Option<u32>,
+    #[serde(default)]
+    base_clock_ghz: Option<f64>,
+    #[serde(default)]
+    turbo_clock_ghz: Option<f64>,
+    #[serde(default)]
+    temperature_logs: Vec<u32>,
+    benchmark_score: u32,
+    #[serde(default)]
+    error_logs: Vec<String>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct GpuInfo {
+    model: String,
+    vram: String,
+    benchmark_fps_fhd: u32,
+    #[serde(default)]
+    stress_test_logs: Vec<u32>,
+    #[serde(default)]
+    outputs_tested: Vec<String>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct LaptopMemory {
+    capacity_gb: u32,
+    #[serde(rename = "type")]
+    memory_type: String,
+    frequency_mhz: u32,
+    health_percent: u32,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct MobileMemory {
+    ram_gb: u32,
+    storage_gb: u32,
+    original: bool,
+    health_percent: u32,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct SmartLogEntry {
+    attribute: String,
+    value: u64,
+    status: String,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct StorageInfo {
+    #[serde(rename = "type")]
+    storage_type: String,
+    capacity_gb: u32,
+    health_percent: u32,
+    read_speed_mbps: u32,
+    write_speed_mbps: u32,
+    #[serde(default)]
+    smart_logs: Vec<SmartLogEntry>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct BatteryInfo {
+    #[serde(rename = "design_capacity_mAh")]
+    design_capacity_mah: u32,
+    #[serde(rename = "current_capacity_mAh")]
+    current_capacity_mah: u32,
+    health_percent: u32,
+    cycle_count: u32,
+    voltage: f64,
+    status: String,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ScreenInfo {
+    size_inches: f64,
+    resolution: String,
+    panel_type: String,
+    refresh_rate_hz: u32,
+    status: String,
+    dead_pixels: u32,
+    touch_functional: bool,
+    #[serde(default)]
+    touch_calibration_logs: Vec<u32>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct KeyboardTrackpad {
+    keyboard_status: String,
+    trackpad_status: String,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct PortsConnectivity {
+    wifi_status: String,
+    bluetooth_status: String,
+    #[serde(default)]
+    ports_tested: Vec<String>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct CameraInfo {
+    rear_camera: String,
+    front_camera: String,
+    #[serde(default)]
+    camera_test_logs: Vec<String>,
+    face_id_touch_id: String,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct MobileConnectivity {
+    wifi_status: String,
+    bluetooth_status: String,
+    cellular_network_test: String,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct LaptopHardware {
+    cpu: CpuInfo,
+    gpu: GpuInfo,
+    memory: LaptopMemory,
+    storage: StorageInfo,
+    battery: BatteryInfo,
+    screen: ScreenInfo,
+    keyboard_trackpad: KeyboardTrackpad,
+    ports_connectivity: PortsConnectivity,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct MobileHardware {
+    cpu: CpuInfo,
+    memory: MobileMemory,
+    battery: BatteryInfo,
+    screen: ScreenInfo,
+    camera: CameraInfo,
+    connectivity: MobileConnectivity,
+}
+
+/// Tablets are inspected with the same sections as phones.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum DeviceHardware {
+    Laptop(LaptopHardware),
+    Mobile(MobileHardware),
+    Tablet(MobileHardware),
+}
+
+impl DeviceHardware {
+    fn category(&self) -> DeviceCategory {
+        match self {
+            DeviceHardware::Laptop(_) => DeviceCategory::Laptop,
+            DeviceHardware::Mobile(_) => DeviceCategory::Mobile,
+            DeviceHardware::Tablet(_) => DeviceCategory::Tablet,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct InspectionSummary {
+    overall_score: u32,
+    #[serde(default)]
+    critical_issues: Vec<String>,
+    #[serde(default)]
+    strengths: Vec<String>,
+    #[serde(default)]
+    weaknesses: Vec<String>,
+    #[serde(default)]
+    best_use_cases: Vec<String>,
+    recommendation: String,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct BlockchainRecord {
+    report_hash: String,
+    timestamp: String,
+}
+
+/// A listing price in minor units (cents) of an ISO 4217 currency.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Price {
+    amount: u64,
+    currency: String,
+}
+
+impl Price {
+    fn validate(&self) -> Result<(), String> {
+        if self.amount == 0 {
+            return Err("Price must be greater than zero".to_string());
+        }
+        if self.currency.len() != 3 || !self.currency.chars().all(|c| c.is_ascii_uppercase()) {
+            return Err("Currency must be a three-letter ISO code such as USD".to_string());
+        }
+        Ok(())
+    }
+
+    /// The amount in US cents, or `None` for other currencies.
+    fn usd_cents(&self) -> Option<u64> {
+        if self.currency == "USD" { Some(self.amount) } else { None }
+    }
+}
+
+/// Where a device is in its listing lifecycle. Devices are never removed once
+/// created so their reports, contracts and orders keep pointing at them.
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum DeviceStatus {
+    /// Not visible in the marketplace yet, e.g. imported without a price.
+    Draft,
+    Listed,
+    /// Checked out by a buyer; waiting for the buyer to confirm the order.
+    Reserved,
+    /// Sold through an order; owned by the buyer until relisted.
+    Sold,
+    Archived,
+}
+
+impl DeviceStatus {
+    /// Whether the device still holds its identifiers against new listings.
+    fn is_active(&self) -> bool {
+        matches!(self, DeviceStatus::Draft | DeviceStatus::Listed | DeviceStatus::Reserved)
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Device {
+    id: u64,
+    user_id: u64,
+    name: String,
+    specs: String,
+    brand: String,
+    model: String,
+    year: u32,
+    identity: DeviceIdentity,
+    hardware: Option<DeviceHardware>,
+    inspection_summary: Option<InspectionSummary>,
+    blockchain: Option<BlockchainRecord>,
+    price: Option<Price>,
+    status: DeviceStatus,
+}
+
+/// What a seller supplies when listing a device.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DeviceInput {
+    name: String,
+    specs: String,
+    brand: String,
+    model: String,
+    year: u32,
+    identity: DeviceIdentity,
+    hardware: Option<DeviceHardware>,
+    inspection_summary: Option<InspectionSummary>,
+    price: Price,
+}
+
+/// `Device` as stored before the typed sections existed (version 1).
+#[derive(Deserialize)]
+struct DeviceV1 {
+    id: u64,
+    user_id: u64,
+    name: String,
+    specs: String,
+}
+
+impl From<DeviceV1> for Device {
+    fn from(d: DeviceV1) -> Self {
+        Device {
+            id: d.id,
+            user_id: d.user_id,
+            name: d.name,
+            specs: d.specs,
+            brand: String::new(),
+            model: String::new(),
+            year: 0,
+            identity: DeviceIdentity::default(),
+            hardware: None,
+            inspection_summary: None,
+            blockchain: None,
+            price: None,
+            status: DeviceStatus::Draft,
+        }
+    }
+}
+
+/// `Device` as stored before listings had a price (version 2).
+#[derive(Deserialize)]
+struct DeviceV2 {
+    id: u64,
+    user_id: u64,
+    name: String,
+    specs: String,
+    brand: String,
+    model: String,
+    year: u32,
+    identity: DeviceIdentity,
+    hardware: Option<DeviceHardware>,
+    inspection_summary: Option<InspectionSummary>,
+    blockchain: Option<BlockchainRecord>,
+}
+
+impl From<DeviceV2> for Device {
+    fn from(d: DeviceV2) -> Self {
+        Device {
+            id: d.id,
+            user_id: d.user_id,
+            name: d.name,
+            specs: d.specs,
+            brand: d.brand,
+            model: d.model,
+            year: d.year,
+            identity: d.identity,
+            hardware: d.hardware,
+            inspection_summary: d.inspection_summary,
+            blockchain: d.blockchain,
+            price: None,
+            status: DeviceStatus::Draft,
+        }
+    }
+}
+
+/// `Device` as stored before the listing lifecycle existed (version 3).
+#[derive(Deserialize)]
+struct DeviceV3 {
+    id: u64,
+    user_id: u64,
+    name: String,
+    specs: String,
+    brand: String,
+    model: String,
+    year: u32,
+    identity: DeviceIdentity,
+    hardware: Option<DeviceHardware>,
+    inspection_summary: Option<InspectionSummary>,
+    blockchain: Option<BlockchainRecord>,
+    price: Option<Price>,
+}
+
+impl From<DeviceV3> for Device {
+    fn from(d: DeviceV3) -> Self {
+        Device {
+            id: d.id,
+            user_id: d.user_id,
+            name: d.name,
+            specs: d.specs,
+            brand: d.brand,
+            model: d.model,
+            year: d.year,
+            identity: d.identity,
+            hardware: d.hardware,
+            inspection_summary: d.inspection_summary,
+            blockchain: d.blockchain,
+            status: if d.price.is_some() { DeviceStatus::Listed } else { DeviceStatus::Draft },
+            price: d.price,
+        }
+    }
+}
+
+impl Storable for Device {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(encode_versioned(self)) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { decode_versioned(&bytes) }
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Versioned for Device {
+    const VERSION: u16 = 4;
+
+    fn upgrade(version: u16, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            1 => {
+                let d: DeviceV1 = bincode::deserialize(payload).map_err(|e| e.to_string())?;
+                Ok(Device::from(d))
+            },
+            2 => {
+                let d: DeviceV2 = bincode::deserialize(payload).map_err(|e| e.to_string())?;
+                Ok(Device::from(d))
+            },
+            3 => {
+                let d: DeviceV3 = bincode::deserialize(payload).map_err(|e| e.to_string())?;
+                Ok(Device::from(d))
+            },
+            _ => Err(format!("no upgrade path from version {}", version)),
+        }
+    }
+}
+
+impl Device {
+    /// Price in US cents, or `None` when the listing is unpriced or priced in
+    /// another currency.
+    fn price_usd_cents(&self) -> Option<u64> {
+        self.price.as_ref().and_then(Price::usd_cents)
+    }
+
+    fn category(&self) -> Option<DeviceCategory> {
+        self.hardware.as_ref().map(DeviceHardware::category)
+    }
+
+    fn cpu(&self) -> Option<&CpuInfo> {
+        match &self.hardware {
+            Some(DeviceHardware::Laptop(h)) => Some(&h.cpu),
+            Some(DeviceHardware::Mobile(h) | DeviceHardware::Tablet(h)) => Some(&h.cpu),
+            None => None,
+        }
+    }
+
+    fn battery(&self) -> Option<&BatteryInfo> {
+        match &self.hardware {
+            Some(DeviceHardware::Laptop(h)) => Some(&h.battery),
+            Some(DeviceHardware::Mobile(h) | DeviceHardware::Tablet(h)) => Some(&h.battery),
+            None => None,
+        }
+    }
+
+    fn ram_gb(&self) -> Option<u32> {
+        match &self.hardware {
+            Some(DeviceHardware::Laptop(h)) => Some(h.memory.capacity_gb),
+            Some(DeviceHardware::Mobile(h) | DeviceHardware::Tablet(h)) => Some(h.memory.ram_gb),
+            None => None,
+        }
+    }
+
+    fn storage_gb(&self) -> Option<u32> {
+        match &self.hardware {
+            Some(DeviceHardware::Laptop(h)) => Some(h.storage.capacity_gb),
+            Some(DeviceHardware::Mobile(h) | DeviceHardware::Tablet(h)) => Some(h.memory.storage_gb),
+            None => None,
+        }
+    }
+
+    fn screen(&self) -> Option<&ScreenInfo> {
+        match &self.hardware {
+            Some(DeviceHardware::Laptop(h)) => Some(&h.screen),
+            Some(DeviceHardware::Mobile(h) | DeviceHardware::Tablet(h)) => Some(&h.screen),
+            None => None,
+        }
+    }
+
+    /// Returns one named section of the device as JSON, e.g. "battery" or "cpu".
+    fn section_json(&self, part: &str) -> Option<String> {
+        let part = part.trim().to_lowercase();
+        let value = match (part.as_str(), &self.hardware) {
+            ("identity", _) => serde_json::to_value(&self.identity),
+            ("inspection_summary" | "summary", _) => serde_json::to_value(self.inspection_summary.as_ref()?),
+            ("cpu", _) => serde_json::to_value(self.cpu()?),
+            ("battery", _) => serde_json::to_value(self.battery()?),
+            ("screen", _) => serde_json::to_value(self.screen()?),
+            ("gpu", Some(DeviceHardware::Laptop(h))) => serde_json::to_value(&h.gpu),
+            ("memory", Some(DeviceHardware::Laptop(h))) => serde_json::to_value(&h.memory),
+            ("memory", Some(DeviceHardware::Mobile(h) | DeviceHardware::Tablet(h))) => serde_json::to_value(&h.memory),
+            ("storage", Some(DeviceHardware::Laptop(h))) => serde_json::to_value(&h.storage),
+            ("keyboard_trackpad" | "keyboard", Some(DeviceHardware::Laptop(h))) => serde_json::to_value(&h.keyboard_trackpad),
+            ("ports_connectivity" | "ports", Some(DeviceHardware::Laptop(h))) => serde_json::to_value(&h.ports_connectivity),
+            ("camera", Some(DeviceHardware::Mobile(h) | DeviceHardware::Tablet(h))) => serde_json::to_value(&h.camera),
+            ("connectivity", Some(DeviceHardware::Mobile(h) | DeviceHardware::Tablet(h))) => serde_json::to_value(&h.connectivity),
+            _ => return None,
+        };
+        value.ok().map(|v| v.to_string())
+    }
+
+    /// Normalized text used by the AI matcher's keyword constraints.
+    fn match_text(&self) -> String {
+        let mut parts = vec![self.name.clone(), self.specs.clone(), self.brand.clone(), self.model.clone()];
+        if let Some(cpu) = self.cpu() {
+            parts.push(cpu.model.clone());
+        }
+        if let Some(summary) = &self.inspection_summary {
+            parts.extend(summary.strengths.iter().cloned());
+            parts.extend(summary.best_use_cases.iter().cloned());
+            parts.extend(summary.critical_issues.iter().cloned());
+        }
+        normalize_text(&parts.join(" "))
+    }
+}
+
+impl DeviceCategory {
+    fn key(&self) -> &'static str {
+        match self {
+            DeviceCategory::Laptop => "laptop",
+            DeviceCategory::Mobile => "mobile",
+            DeviceCategory::Tablet => "tablet",
+        }
+    }
+
+    /// Maps the free-text device type produced by the AI model to a category.
+    fn from_label(label: &str) -> Option<DeviceCategory> {
+        match label.trim().to_lowercase().as_str() {
+            "laptop" | "notebook" | "ultrabook" => Some(DeviceCategory::Laptop),
+            "mobile" | "phone" | "smartphone" | "mobile phone" => Some(DeviceCategory::Mobile),
+            "tablet" | "ipad" => Some(DeviceCategory::Tablet),
+            _ => None,
+        }
+    }
+}
+
+/// --------- WARRANTY CONTRACT STRUCT ----------
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct WarrantyContract {
+    id: u64,
+    seller_id: u64,
+    buyer_id: u64,
+    device_id: u64,
+    warranty_months: u32,
+    expiry_date: u64,
+}
+
+impl Storable for WarrantyContract {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(encode_versioned(self)) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { decode_versioned(&bytes) }
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Versioned for WarrantyContract {
+    const VERSION: u16 = 1;
+}
+
+/// --------- REPORT STRUCT ----------
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Report {
+    id: u64,
+    device_id: u64,
+    specialist_name: String,
+    notes: String,
+    timestamp: u64,
+    /// Version of the category checklist the answers follow; `None` for
+    /// free-form reports filed while the category had no checklist.
+    template_version: Option<u32>,
+    answers: Vec<ChecklistAnswer>,
+    /// Hex SHA-256 of the canonical serialization; see `canonical_report`.
+    content_hash: String,
+}
+
+/// `Report` as stored before checklists existed (version 1).
+#[derive(Deserialize)]
+struct ReportV1 {
+    id: u64,
+    device_id: u64,
+    specialist_name: String,
+    notes: String,
+    timestamp: u64,
+}
+
+impl From<ReportV1> for ReportV2 {
+    fn from(r: ReportV1) -> Self {
+        ReportV2 {
+            id: r.id,
+            device_id: r.device_id,
+            specialist_name: r.specialist_name,
+            notes: r.notes,
+            timestamp: r.timestamp,
+            template_version: None,
+            answers: Vec::new(),
+        }
+    }
+}
+
+/// `Report` as stored before reports were hashed (version 2).
+#[derive(Deserialize)]
+struct ReportV2 {
+    id: u64,
+    device_id: u64,
+    specialist_name: String,
+    notes: String,
+    timestamp: u64,
+    template_version: Option<u32>,
+    answers: Vec<ChecklistAnswer>,
+}
+
+impl From<ReportV2> for Report {
+    fn from(r: ReportV2) -> Self {
+        let mut report = Report {
+            id: r.id,
+            device_id: r.device_id,
+            specialist_name: r.specialist_name,
+            notes: r.notes,
+            timestamp: r.timestamp,
+            template_version: r.template_version,
+            answers: r.answers,
+            content_hash: String::new(),
+        };
+        report.content_hash = sha256_hex(&canonical_report(&report));
+        report
+    }
+}
+
+/// Canonical form of a report for hashing: compact JSON with the keys in
+/// alphabetical order. `content_hash` itself is left out.
+#[derive(Serialize)]
+struct CanonicalReport<'a> {
+    answers: &'a [ChecklistAnswer],
+    device_id: u64,
+    id: u64,
+    notes: &'a str,
+    specialist_name: &'a str,
+    template_version: Option<u32>,
+    timestamp: u64,
+}
+
+fn canonical_report(report: &Report) -> Vec<u8> {
+    serde_json::to_vec(&CanonicalReport {
+        answers: &report.answers,
+        device_id: report.device_id,
+        id: report.id,
+        notes: &report.notes,
+        specialist_name: &report.specialist_name,
+        template_version: report.template_version,
+        timestamp: report.timestamp,
+    }).unwrap()
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+fn report_digest(report: &Report) -> Hash {
+    Sha256::digest(canonical_report(report)).into()
+}
+
+impl Storable for Report {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(encode_versioned(self)) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { decode_versioned(&bytes) }
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Versioned for Report {
+    const VERSION: u16 = 3;
+
+    fn upgrade(version: u16, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            1 => {
+                let r: ReportV1 = bincode::deserialize(payload).map_err(|e| e.to_string())?;
+                Ok(Report::from(ReportV2::from(r)))
+            },
+            2 => {
+                let r: ReportV2 = bincode::deserialize(payload).map_err(|e| e.to_string())?;
+                Ok(Report::from(r))
+            },
+            _ => Err(format!("no upgrade path from version {}", version)),
+        }
+    }
+}
+
+/// What a buyer presents to `verify_report`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum ReportEvidence {
+    /// The report document itself, as returned by `get_report_document`.
+    Document(#[serde(with = "serde_bytes")] Vec<u8>),
+    /// A hex SHA-256 computed off-chain.
+    Hash(String),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ReportVerification {
+    report_id: u64,
+    matches: bool,
+    stored_hash: String,
+    provided_hash: String,
+}
+
+/// A report with the data a client needs to check it against the subnet's
+/// signature without an update call: `certificate` certifies the canister's
+/// certified data, and `witness` (CBOR hash tree) proves that
+/// `["origin_trace", "report/<id>"]` holds the SHA-256 of `document`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CertifiedReport {
+    report: Report,
+    document: String,
+    #[serde(with = "serde_bytes")]
+    certificate: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    witness: Vec<u8>,
+}
+
+/// --------- CHECKLIST TEMPLATE STRUCTS ----------
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum ChecklistValueType {
+    Bool,
+    Number,
+    Text,
+    /// One of the listed options.
+    Choice(Vec<String>),
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ChecklistItem {
+    key: String,
+    label: String,
+    value_type: ChecklistValueType,
+    required: bool,
+}
+
+/// The checks a specialist must go through for one device category.
+/// `version` increases every time an admin replaces the template.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ChecklistTemplate {
+    category: DeviceCategory,
+    version: u32,
+    items: Vec<ChecklistItem>,
+    updated_by: Principal,
+    updated_at: u64,
+}
+
+impl Storable for ChecklistTemplate {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(encode_versioned(self)) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { decode_versioned(&bytes) }
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Versioned for ChecklistTemplate {
+    const VERSION: u16 = 1;
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum ChecklistValue {
+    Bool(bool),
+    Number(f64),
+    Text(String),
+    Choice(String),
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct ChecklistAnswer {
+    key: String,
+    value: ChecklistValue,
+}
+
+/// --------- PRICE HISTORY STRUCT ----------
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct PriceChange {
+    id: u64,
+    device_id: u64,
+    old_price: Option<Price>,
+    new_price: Price,
+    changed_by: u64,
+    changed_at: u64,
+}
+
+impl Storable for PriceChange {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(encode_versioned(self)) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { decode_versioned(&bytes) }
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Versioned for PriceChange {
+    const VERSION: u16 = 1;
+}
+
+/// --------- OWNERSHIP LOG STRUCT ----------
+/// What justified an ownership record.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum OwnershipEvent {
+    /// The device was first listed on the platform by its owner.
+    Registered,
+    /// A completed order, by order id.
+    Order(u64),
+    /// A transfer made outside the marketplace, optionally backed by a
+    /// warranty contract.
+    Transfer { contract_id: Option<u64>, note: String },
+}
+
+/// One hop in a device's ownership chain. Records are only ever appended.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct OwnershipRecord {
+    id: u64,
+    device_id: u64,
+    from_user_id: Option<u64>,
+    to_user_id: u64,
+    event: OwnershipEvent,
+    recorded_at: u64,
+}
+
+impl Storable for OwnershipRecord {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(encode_versioned(self)) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { decode_versioned(&bytes) }
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Versioned for OwnershipRecord {
+    const VERSION: u16 = 1;
+}
+
+/// --------- BLACKLIST STRUCT ----------
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum BlacklistReason {
+    Lost,
+    Stolen,
+}
+
+/// One lost/stolen report. Entries are never deleted; clearing one only
+/// fills in `cleared_by` / `cleared_at`.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct BlacklistEntry {
+    id: u64,
+    identifier: String,
+    keys: Vec<String>,
+    device_id: Option<u64>,
+    reason: BlacklistReason,
+    note: String,
+    reported_by: Principal,
+    reported_at: u64,
+    cleared_by: Option<Principal>,
+    cleared_at: Option<u64>,
+}
+
+impl Storable for BlacklistEntry {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(encode_versioned(self)) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { decode_versioned(&bytes) }
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Versioned for BlacklistEntry {
+    const VERSION: u16 = 1;
+}
+
+/// --------- CART STRUCT ----------
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Cart {
+    user_id: u64,
+    device_ids: Vec<u64>,
+}
+
+impl Storable for Cart {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(encode_versioned(self)) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { decode_versioned(&bytes) }
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Versioned for Cart {
+    const VERSION: u16 = 1;
+}
+
+/// --------- ORDER STRUCT ----------
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Order {
+    id: u64,
+    buyer_id: u64,
+    seller_id: u64,
+    device_id: u64,
+    timestamp: u64,
+    price: Option<Price>,
+    status: OrderStatus,
+    closed_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum OrderStatus {
+    /// The device is reserved for the buyer.
+    Pending,
+    /// The buyer confirmed the order and now owns the device.
+    Completed,
+    Cancelled,
+}
+
+/// `Order` as stored before orders had a status (version 1). Those orders
+/// removed the device outright, so they are treated as completed.
+#[derive(Deserialize)]
+struct OrderV1 {
+    id: u64,
+    buyer_id: u64,
+    seller_id: u64,
+    device_id: u64,
+    timestamp: u64,
+}
+
+impl From<OrderV1> for Order {
+    fn from(o: OrderV1) -> Self {
+        Order {
+            id: o.id,
+            buyer_id: o.buyer_id,
+            seller_id: o.seller_id,
+            device_id: o.device_id,
+            timestamp: o.timestamp,
+            price: None,
+            status: OrderStatus::Completed,
+            closed_at: Some(o.timestamp),
+        }
+    }
+}
+
+impl Storable for Order {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(encode_versioned(self)) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { decode_versioned(&bytes) }
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Versioned for Order {
+    const VERSION: u16 = 2;
+
+    fn upgrade(version: u16, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            1 => {
+                let o: OrderV1 = bincode::deserialize(payload).map_err(|e| e.to_string())?;
+                Ok(Order::from(o))
+            },
+            _ => Err(format!("no upgrade path from version {}", version)),
+        }
+    }
+}
+
+/// --------- SPECIALIST ACCREDITATION STRUCTS ----------
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum ApplicationStatus {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct SpecialistApplication {
+    id: u64,
+    user_id: u64,
+    credentials: String,
+    submitted_at: u64,
+    status: ApplicationStatus,
+    reviewed_by: Option<Principal>,
+    reviewed_at: Option<u64>,
+    review_note: String,
+}
+
+impl Storable for SpecialistApplication {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(encode_versioned(self)) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { decode_versioned(&bytes) }
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Versioned for SpecialistApplication {
+    const VERSION: u16 = 1;
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Accreditation {
+    user_id: u64,
+    application_id: u64,
+    granted_by: Principal,
+    granted_at: u64,
+    expires_at: u64,
+}
+
+impl Storable for Accreditation {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(encode_versioned(self)) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { decode_versioned(&bytes) }
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Versioned for Accreditation {
+    const VERSION: u16 = 1;
+}
+
+/// --------- PLATFORM SETTINGS ----------
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Settings {
+    ai_endpoint_url: String,
+    platform_fee_bps: u32,
+    max_devices_per_seller: u32,
+    max_cart_items: u32,
+    /// Threshold ECDSA key used to sign device certificates: "dfx_test_key"
+    /// on a local replica, "test_key_1" or "key_1" on mainnet.
+    ecdsa_key_name: String,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            ai_endpoint_url: "https://rawan7-icp-ai-agent-api2.hf.space/generate".to_string(),
+            platform_fee_bps: 0,
+            max_devices_per_seller: 500,
+            max_cart_items: 20,
+            ecdsa_key_name: "dfx_test_key".to_string(),
+        }
+    }
+}
+
+/// `Settings` as stored before device certificates were signed (version 1).
+#[derive(Deserialize)]
+struct SettingsV1 {
+    ai_endpoint_url: String,
+    platform_fee_bps: u32,
+    max_devices_per_seller: u32,
+    max_cart_items: u32,
+}
+
+impl Storable for Settings {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(encode_versioned(self)) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { decode_versioned(&bytes) }
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Versioned for Settings {
+    const VERSION: u16 = 2;
+
+    fn upgrade(version: u16, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            1 => {
+                let s: SettingsV1 = bincode::deserialize(payload).map_err(|e| e.to_string())?;
+                Ok(Settings {
+                    ai_endpoint_url: s.ai_endpoint_url,
+                    platform_fee_bps: s.platform_fee_bps,
+                    max_devices_per_seller: s.max_devices_per_seller,
+                    max_cart_items: s.max_cart_items,
+                    ..Settings::default()
+                })
+            },
+            _ => Err(format!("no upgrade path from version {}", version)),
+        }
+    }
+}
+
+/// --------- DEVICE CERTIFICATE STRUCTS ----------
+/// What a device certificate attests. The signed document is this struct as
+/// compact JSON, so the fields must stay in alphabetical order.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct CertificatePayload {
+    canister_id: Principal,
+    device_id: u64,
+    imei: Option<String>,
+    issued_at: u64,
+    owner_id: u64,
+    owner_principal: Principal,
+    report_hash: String,
+    report_id: u64,
+    serial_number: String,
+}
+
+/// A certificate signed with the canister's threshold ECDSA key: `signature`
+/// is the 64-byte secp256k1 (r, s) over the SHA-256 of the payload document.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct DeviceCertificate {
+    id: u64,
+    payload: CertificatePayload,
+    key_name: String,
+    #[serde(with = "serde_bytes")]
+    signature: Vec<u8>,
+}
+
+impl Storable for DeviceCertificate {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(encode_versioned(self)) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { decode_versioned(&bytes) }
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Versioned for DeviceCertificate {
+    const VERSION: u16 = 1;
+}
+
+/// SEC1 compressed secp256k1 key that verifies certificates signed with
+/// `key_name`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CertificatePublicKey {
+    key_name: String,
+    #[serde(with = "serde_bytes")]
+    public_key: Vec<u8>,
+}
+
+/// A device certificate with the IC certificate and witness proving that
+/// `["origin_trace", "certificate/<id>"]` holds the SHA-256 of `document`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CertifiedDeviceCertificate {
+    device_certificate: DeviceCertificate,
+    document: String,
+    #[serde(with = "serde_bytes")]
+    certificate: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    witness: Vec<u8>,
+}
+
+/// Portable form of a certificate for QR codes and files, read by the
+/// offline verifier.
+#[derive(Serialize)]
+struct CertificateExport<'a> {
+    format: &'static str,
+    certificate_id: u64,
+    key_name: &'a str,
+    /// The exact signed bytes, i.e. the payload as canonical JSON.
+    document: String,
+    signature: String,
+    public_key: Option<String>,
+}
+
+/// --------- LISTING POLICY ----------
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum LockAction {
+    Allow,
+    Warn,
+    Block,
+}
+
+/// What happens to a listing whose inspection recorded a lock or tampering.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ListingPolicy {
+    frp_lock: LockAction,
+    bios_lock: LockAction,
+    carrier_lock: LockAction,
+    tampered: LockAction,
+}
+
+impl Default for ListingPolicy {
+    fn default() -> Self {
+        ListingPolicy {
+            frp_lock: LockAction::Block,
+            bios_lock: LockAction::Block,
+            carrier_lock: LockAction::Warn,
+            tampered: LockAction::Warn,
+        }
+    }
+}
+
+impl Storable for ListingPolicy {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(encode_versioned(self)) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { decode_versioned(&bytes) }
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Versioned for ListingPolicy {
+    const VERSION: u16 = 1;
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum ListingWarning {
+    FrpLocked,
+    BiosLocked,
+    CarrierLocked,
+    Tampered,
+}
+
+impl ListingPolicy {
+    /// Lock conditions present on the device, each with its configured action.
+    fn evaluate(&self, identity: &DeviceIdentity) -> Vec<(ListingWarning, LockAction)> {
+        let mut found = Vec::new();
+        if identity.icloud_frp_lock {
+            found.push((ListingWarning::FrpLocked, self.frp_lock.clone()));
+        }
+        if identity.bios_lock {
+            found.push((ListingWarning::BiosLocked, self.bios_lock.clone()));
+        }
+        if identity.carrier_lock {
+            found.push((ListingWarning::CarrierLocked, self.carrier_lock.clone()));
+        }
+        if !identity.tamper_flags.is_empty() {
+            found.push((ListingWarning::Tampered, self.tampered.clone()));
+        }
+        found
+    }
+
+    fn blocks(&self, identity: &DeviceIdentity) -> bool {
+        self.evaluate(identity).iter().any(|(_, action)| *action == LockAction::Block)
+    }
+}
+
+/// A device as shown in listing queries, with the policy warnings buyers
+/// should see next to it.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DeviceListing {
+    device: Device,
+    warnings: Vec<ListingWarning>,
+}
+
+/// Everything recorded about one device, for the device page.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DevicePassport {
+    device: Device,
+    warnings: Vec<ListingWarning>,
+    reports: Vec<Report>,
+    contracts: Vec<WarrantyContract>,
+    ownership: Vec<OwnershipRecord>,
+    orders: Vec<Order>,
+    price_history: Vec<PriceChange>,
+}
+
+/// --------- PAGINATION ----------
+/// Largest page any paginated query returns.
+const MAX_PAGE_SIZE: u32 = 100;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum SortOrder {
+    Newest,
+    Oldest,
+    /// Compares US-dollar prices; records that are unpriced or priced in
+    /// another currency come last.
+    PriceLowToHigh,
+    PriceHighToLow,
+}
+
+/// `cursor` is the `next_cursor` of the previous page, or `None` for the
+/// first page. It is only valid with the same sort order.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PageRequest {
+    cursor: Option<String>,
+    limit: u32,
+    sort: SortOrder,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DevicePage {
+    items: Vec<DeviceListing>,
+    next_cursor: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ReportPage {
+    items: Vec<Report>,
+    next_cursor: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ContractPage {
+    items: Vec<WarrantyContract>,
+    next_cursor: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct OrderPage {
+    items: Vec<Order>,
+    next_cursor: Option<String>,
+}
+
+/// --------- DEVICE SEARCH ----------
+/// Criteria for `search_devices`; every field that is set must match. Text
+/// fields compare case-insensitively, prices are in US cents and devices
+/// without the inspected value are excluded by the matching minimum.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct DeviceFilter {
+    category: Option<DeviceCategory>,
+    brand: Option<String>,
+    /// Matches any model containing this text.
+    model: Option<String>,
+    min_year: Option<u32>,
+    max_year: Option<u32>,
+    min_price: Option<u64>,
+    max_price: Option<u64>,
+    min_ram_gb: Option<u32>,
+    min_storage_gb: Option<u32>,
+    min_battery_health: Option<u32>,
+    min_overall_score: Option<u32>,
+    /// `Some(false)` keeps only devices without FRP, BIOS or carrier locks.
+    locked: Option<bool>,
+}
+
+impl DeviceFilter {
+    fn matches(&self, device: &Device) -> bool {
+        fn at_least(value: Option<u32>, min: Option<u32>) -> bool {
+            match min {
+                Some(min) => value.map(|v| v >= min).unwrap_or(false),
+                None => true,
+            }
+        }
+        if self.category.is_some() && device.category() != self.category {
+            return false;
+        }
+        if let Some(brand) = &self.brand {
+            if !device.brand.eq_ignore_ascii_case(brand.trim()) {
+                return false;
+            }
+        }
+        if let Some(model) = &self.model {
+            if !device.model.to_lowercase().contains(&model.trim().to_lowercase()) {
+                return false;
+            }
+        }
+        if self.min_year.map(|y| device.year < y).unwrap_or(false) || self.max_year.map(|y| device.year > y).unwrap_or(false) {
+            return false;
+        }
+        if self.min_price.is_some() || self.max_price.is_some() {
+            match device.price_usd_cents() {
+                Some(cents) if self.min_price.map(|p| cents >= p).unwrap_or(true) && self.max_price.map(|p| cents <= p).unwrap_or(true) => {},
+                _ => return false,
+            }
+        }
+        if let Some(locked) = self.locked {
+            let identity = &device.identity;
+            if (identity.icloud_frp_lock || identity.bios_lock || identity.carrier_lock) != locked {
+                return false;
+            }
+        }
+        at_least(device.ram_gb(), self.min_ram_gb)
+            && at_least(device.storage_gb(), self.min_storage_gb)
+            && at_least(device.battery().map(|b| b.health_percent), self.min_battery_health)
+            && at_least(device.inspection_summary.as_ref().map(|s| s.overall_score), self.min_overall_score)
+    }
+}
+
+/// One `search_text` hit. Results are ranked by how many distinct query terms
+/// matched, then by `score`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TextMatch {
+    device_id: u64,
+    matched_terms: u32,
+    score: u32,
+}
+
+/// --------- DEVICE COMPARISON ----------
+/// Most devices `compare_devices` accepts at once.
+const MAX_COMPARED_DEVICES: usize = 4;
+
+/// One attribute across the compared devices. `values` follow the order of
+/// `DeviceComparison.device_ids`; `None` means the device has no such data.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ComparisonRow {
+    attribute: String,
+    higher_is_better: bool,
+    values: Vec<Option<u64>>,
+    /// Device with the strictly best value, if any.
+    winner: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DeviceComparison {
+    device_ids: Vec<u64>,
+    rows: Vec<ComparisonRow>,
+    summary: Vec<String>,
+}
+
+/// --------- STORAGE ----------
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static USERS: RefCell<StableBTreeMap<u64, User, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))))
+    );
+
+    static DEVICES: RefCell<StableBTreeMap<u64, Device, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))))
+    );
+
+    static CONTRACTS: RefCell<StableBTreeMap<u64, WarrantyContract, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))))
+    );
+
+    static REPORTS: RefCell<StableBTreeMap<u64, Report, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))))
+    );
+
+    static CARTS: RefCell<StableBTreeMap<u64, Cart, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))))
+    );
+
+    static ORDERS: RefCell<StableBTreeMap<u64, Order, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))))
+    );
+
+    // principal -> user id, so every call can be tied to the real caller
+    static PRINCIPALS: RefCell<StableBTreeMap<Principal, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))))
+    );
+
+    static SCHEMA_VERSION: RefCell<StableCell<u16, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))), 0)
+            .expect("Failed to initialize the schema version cell")
+    );
+
+    static SETTINGS: RefCell<StableCell<Settings, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))), Settings::default())
+            .expect("Failed to initialize the settings cell")
+    );
+
+    static APPLICATIONS: RefCell<StableBTreeMap<u64, SpecialistApplication, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))))
+    );
+
+    // user id -> current accreditation
+    static ACCREDITATIONS: RefCell<StableBTreeMap<u64, Accreditation, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))))
+    );
+
+    // (device id, change id) -> price change
+    static PRICE_HISTORY: RefCell<StableBTreeMap<(u64, u64), PriceChange, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))))
+    );
+
+    // "imei:<digits>" / "sn:<SERIAL>" -> device id
+    static IDENTIFIERS: RefCell<StableBTreeMap<String, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))))
+    );
+
+    static BLACKLIST: RefCell<StableBTreeMap<u64, BlacklistEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))))
+    );
+
+    // identifier key -> id of the blacklist entry currently in force
+    static ACTIVE_BLACKLIST: RefCell<StableBTreeMap<String, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))))
+    );
+
+    static LISTING_POLICY: RefCell<StableCell<ListingPolicy, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))), ListingPolicy::default())
+            .expect("Failed to initialize the listing policy cell")
+    );
+
+    // (device id, record id) -> ownership record, in the order they happened
+    static OWNERSHIP: RefCell<StableBTreeMap<(u64, u64), OwnershipRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))))
+    );
+
+    // Secondary indexes; kept in sync wherever the indexed maps change
+    static DEVICES_BY_OWNER: RefCell<IdIndex> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))))
+    );
+
+    static REPORTS_BY_DEVICE: RefCell<IdIndex> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))))
+    );
+
+    static ORDERS_BY_BUYER: RefCell<IdIndex> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))))
+    );
+
+    static ORDERS_BY_SELLER: RefCell<IdIndex> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))))
+    );
+
+    static ORDERS_BY_DEVICE: RefCell<IdIndex> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))))
+    );
+
+    static CONTRACTS_BY_DEVICE: RefCell<IdIndex> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))))
+    );
+
+    // category key (see `DeviceCategory::key`) -> current checklist template
+    static CHECKLISTS: RefCell<StableBTreeMap<String, ChecklistTemplate, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25))))
+    );
+
+    // "term\0device id\0source id" -> weight; see `posting_key`
+    static TEXT_INDEX: RefCell<StableBTreeMap<String, u32, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))))
+    );
+
+    static CERTIFICATES: RefCell<StableBTreeMap<u64, DeviceCertificate, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))))
+    );
+
+    static CERTIFICATES_BY_DEVICE: RefCell<IdIndex> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27))))
+    );
+
+    // ECDSA key name -> public key, fetched the first time the key signs
+    static CERTIFICATE_KEYS: RefCell<StableBTreeMap<String, Vec<u8>, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28))))
+    );
+
+    // Heap-only; rebuilt from stable memory by `rebuild_certified_tree`
+    static CERTIFIED: RefCell<RbTree<String, Hash>> = RefCell::new(RbTree::new());
+
+    // user, device, contract, report, cart, order, application, price change, blacklist entry, ownership record, certificate
+    static COUNTERS: RefCell<StableBTreeMap<u8, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))))
+    );
+}
+
+fn next_id(index: usize) -> u64 {
+    COUNTERS.with(|c| {
+        let mut counters = c.borrow_mut();
+        let id = counters.get(&(index as u8)).unwrap_or(0) + 1;
+        counters.insert(index as u8, id);
+        id
+    })
+}
+
+/// Raises a counter so it is never below the highest id already stored.
+/// Canisters installed before the counters were persisted start from zero
+/// after an upgrade, which would hand out ids that are already taken.
+fn sync_counter(index: usize, max_key: Option<u64>) {
+    if let Some(max_key) = max_key {
+        COUNTERS.with(|c| {
+            let mut counters = c.borrow_mut();
+            if counters.get(&(index as u8)).unwrap_or(0) < max_key {
+                counters.insert(index as u8, max_key);
+            }
+        });
+    }
+}
+
+/// --------- SECONDARY INDEXES ----------
+fn index_insert(index: &'static LocalKey<RefCell<IdIndex>>, key: u64, id: u64) {
+    index.with(|i| i.borrow_mut().insert((key, id), ()));
+}
+
+fn index_remove(index: &'static LocalKey<RefCell<IdIndex>>, key: u64, id: u64) {
+    index.with(|i| i.borrow_mut().remove(&(key, id)));
+}
+
+/// Ids stored under `key`, in ascending order.
+fn index_ids(index: &'static LocalKey<RefCell<IdIndex>>, key: u64) -> Vec<u64> {
+    index.with(|i| i.borrow().range((key, 0)..=(key, u64::MAX)).map(|((_, id), _)| id).collect())
+}
+
+fn index_order(order: &Order) {
+    index_insert(&ORDERS_BY_BUYER, order.buyer_id, order.id);
+    index_insert(&ORDERS_BY_SELLER, order.seller_id, order.id);
+    index_insert(&ORDERS_BY_DEVICE, order.device_id, order.id);
+}
+
+/// Moves the device to `new_owner` in `DEVICES_BY_OWNER`. The caller updates
+/// `Device.user_id` itself.
+fn reindex_owner(device_id: u64, old_owner: u64, new_owner: u64) {
+    index_remove(&DEVICES_BY_OWNER, old_owner, device_id);
+    index_insert(&DEVICES_BY_OWNER, new_owner, device_id);
+}
+
+/// --------- CERTIFIED DATA ----------
+/// Label the certified tree hangs under in witnesses.
+const CERTIFIED_LABEL: &[u8] = b"origin_trace";
+
+fn report_tree_key(report_id: u64) -> String {
+    format!("report/{}", report_id)
+}
+
+fn certificate_tree_key(certificate_id: u64) -> String {
+    format!("certificate/{}", certificate_id)
+}
+
+fn update_certified_data() {
+    let root = CERTIFIED.with(|t| ic_certification::labeled_hash(CERTIFIED_LABEL, &t.borrow().root_hash()));
+    ic_cdk::api::certified_data_set(root);
+}
+
+fn certify(key: String, hash: Hash) {
+    CERTIFIED.with(|t| t.borrow_mut().insert(key, hash));
+    update_certified_data();
+}
+
+fn uncertify(key: &str) {
+    CERTIFIED.with(|t| t.borrow_mut().delete(key.as_bytes()));
+    update_certified_data();
+}
+
+/// The tree lives on the heap, so it is rebuilt after every upgrade.
+fn rebuild_certified_tree() {
+    let reports: Vec<(u64, Hash)> = REPORTS.with(|r| r.borrow().iter().map(|(id, r)| (id, report_digest(&r))).collect());
+    let certificates: Vec<(u64, Hash)> = CERTIFICATES.with(|c| c.borrow().iter()
+        .map(|(id, c)| (id, Sha256::digest(certificate_document(&c.payload)).into()))
+        .collect());
+    CERTIFIED.with(|t| {
+        let mut tree = t.borrow_mut();
+        for (id, hash) in reports {
+            tree.insert(report_tree_key(id), hash);
+        }
+        for (id, hash) in certificates {
+            tree.insert(certificate_tree_key(id), hash);
+        }
+    });
+    update_certified_data();
+}
+
+/// CBOR-encoded witness for `key`, labelled like the certified root.
+fn certified_witness(key: &str) -> Vec<u8> {
+    let tree = CERTIFIED.with(|t| ic_certification::labeled(CERTIFIED_LABEL, t.borrow().witness(key.as_bytes())));
+    let mut serializer = serde_cbor::ser::Serializer::new(Vec::new());
+    serializer.self_describe().unwrap();
+    tree.serialize(&mut serializer).unwrap();
+    serializer.into_inner()
+}
+
+/// --------- MIGRATIONS ----------
+/// Schema version of the whole canister. Version 0 is the original layout of
+/// raw bincode records without an envelope.
+const CURRENT_SCHEMA_VERSION: u16 = 5;
+
+/// Each entry rewrites stable memory from the previous schema version to the
+/// one it is listed with. Append new migrations at the end.
+const MIGRATIONS: &[(u16, fn())] = &[
+    (1, wrap_legacy_records),
+    (2, index_device_identifiers),
+    (3, seed_ownership_log),
+    (4, build_secondary_indexes),
+    (5, build_text_index),
+];
+
+fn run_migrations() {
+    let stored = SCHEMA_VERSION.with(|v| *v.borrow().get());
+    for (version, migration) in MIGRATIONS {
+        if *version > stored {
+            ic_cdk::println!("Running storage migration to schema version {}", version);
+            migration();
+            set_schema_version(*version);
+        }
+    }
+}
+
+fn set_schema_version(version: u16) {
+    SCHEMA_VERSION.with(|v| v.borrow_mut().set(version).expect("Failed to store the schema version"));
+}
+
+/// Rewrites every value of the map in `memory_id` through `convert`, reading
+/// the values as raw bytes. Must run before the typed map is first touched.
+fn rewrite_map<F>(memory_id: u8, convert: F)
+where
+    F: Fn(u64, &[u8]) -> Result<Vec<u8>, String>,
+{
+    let memory = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_id)));
+    let mut raw: StableBTreeMap<u64, Vec<u8>, Memory> = StableBTreeMap::init(memory);
+    let entries: Vec<(u64, Vec<u8>)> = raw.iter().collect();
+    for (key, bytes) in entries {
+        match convert(key, &bytes) {
+            Ok(converted) => { raw.insert(key, converted); },
+            Err(e) => ic_cdk::trap(format!("Failed to migrate record {} in memory {}: {}", key, memory_id, e)),
+        }
+    }
+}
+
+fn wrap_legacy<L, T, F>(bytes: &[u8], convert: F) -> Result<Vec<u8>, String>
+where
+    L: DeserializeOwned,
+    T: Versioned,
+    F: Fn(L) -> T,
+{
+    let legacy: L = bincode::deserialize(bytes).map_err(|e| e.to_string())?;
+    Ok(encode_versioned(&convert(legacy)))
+}
+
+/// Users stored before principals were tracked.
+#[derive(Deserialize)]
+struct LegacyUser {
+    id: u64,
+    name: String,
+    email: String,
+    role: Role,
+}
+
+/// v0 -> v1: wrap every record in an `Envelope`. Legacy users had no principal,
+/// so they are kept under the anonymous principal and cannot sign in until an
+/// admin links them with `link_user_principal`.
+fn wrap_legacy_records() {
+    rewrite_map(0, |_, bytes| wrap_legacy(bytes, |u: LegacyUser| {
+        User::from_single_role(u.id, Principal::anonymous(), u.name, u.email, u.role, false)
+    }));
+    rewrite_map(1, |_, bytes| wrap_legacy(bytes, |d: DeviceV1| Device::from(d)));
+    rewrite_map(2, |_, bytes| wrap_legacy(bytes, |c: WarrantyContract| c));
+    rewrite_map(3, |_, bytes| wrap_legacy(bytes, |r: ReportV1| Report::from(ReportV2::from(r))));
+    rewrite_map(4, |_, bytes| wrap_legacy(bytes, |c: Cart| c));
+    rewrite_map(5, |_, bytes| wrap_legacy(bytes, |o: OrderV1| Order::from(o)));
+}
+
+/// v1 -> v2: build the identifier registry for devices listed before it existed.
+/// When two devices share an identifier the older listing keeps it.
+fn index_device_identifiers() {
+    let devices: Vec<Device> = DEVICES.with(|d| d.borrow().iter().map(|(_, d)| d.clone()).collect());
+    for device in devices {
+        for key in identifier_keys(&device.identity) {
+            IDENTIFIERS.with(|i| {
+                let mut index = i.borrow_mut();
+                if !index.contains_key(&key) {
+                    index.insert(key, device.id);
+                }
+            });
+        }
+    }
+}
+
+/// v2 -> v3: start the ownership log of existing devices. The first owner is
+/// the seller of the earliest completed order, or the current owner, and is
+/// dated by the first recorded price when there is one.
+fn seed_ownership_log() {
+    let mut completed: std::collections::HashMap<u64, Vec<Order>> = std::collections::HashMap::new();
+    ORDERS.with(|o| {
+        for (_, order) in o.borrow().iter().filter(|(_, o)| o.status == OrderStatus::Completed) {
+            completed.entry(order.device_id).or_default().push(order);
+        }
+    });
+    let devices: Vec<Device> = DEVICES.with(|d| d.borrow().iter().map(|(_, d)| d.clone()).collect());
+    for device in devices {
+        let mut orders = completed.remove(&device.id).unwrap_or_default();
+        orders.sort_by_key(|o| o.closed_at.unwrap_or(o.timestamp));
+        let first_owner = orders.first().map(|o| o.seller_id).unwrap_or(device.user_id);
+        let listed_at = PRICE_HISTORY.with(|h| h.borrow().range((device.id, 0)..=(device.id, u64::MAX)).next().map(|(_, c)| c.changed_at))
+            .unwrap_or_else(time);
+        append_ownership(device.id, None, first_owner, OwnershipEvent::Registered, listed_at);
+        for order in orders {
+            append_ownership(device.id, Some(order.seller_id), order.buyer_id, OwnershipEvent::Order(order.id), order.closed_at.unwrap_or(order.timestamp));
+        }
+    }
+}
+
+/// v3 -> v4: index the records stored before the secondary indexes existed.
+fn build_secondary_indexes() {
+    let owners: Vec<(u64, u64)> = DEVICES.with(|d| d.borrow().iter().map(|(id, d)| (d.user_id, id)).collect());
+    for (owner, device_id) in owners {
+        index_insert(&DEVICES_BY_OWNER, owner, device_id);
+    }
+    let reports: Vec<(u64, u64)> = REPORTS.with(|r| r.borrow().iter().map(|(id, r)| (r.device_id, id)).collect());
+    for (device_id, report_id) in reports {
+        index_insert(&REPORTS_BY_DEVICE, device_id, report_id);
+    }
+    let contracts: Vec<(u64, u64)> = CONTRACTS.with(|c| c.borrow().iter().map(|(id, c)| (c.device_id, id)).collect());
+    for (device_id, contract_id) in contracts {
+        index_insert(&CONTRACTS_BY_DEVICE, device_id, contract_id);
+    }
+    let orders: Vec<Order> = ORDERS.with(|o| o.borrow().iter().map(|(_, o)| o.clone()).collect());
+    for order in orders {
+        index_order(&order);
+    }
+}
+
+/// v4 -> v5: index the text of existing devices and reports.
+fn build_text_index() {
+    let devices: Vec<Device> = DEVICES.with(|d| d.borrow().iter().map(|(_, d)| d.clone()).collect());
+    for device in devices {
+        index_device_text(&device);
+    }
+    let reports: Vec<Report> = REPORTS.with(|r| r.borrow().iter().map(|(_, r)| r.clone()).collect());
+    for report in reports {
+        index_report_text(&report);
+    }
+}
+
+/// --------- UPGRADE HOOKS ----------
+#[ic_cdk::init]
+fn init() {
+    set_schema_version(CURRENT_SCHEMA_VERSION);
+    update_certified_data();
+}
+
+// All state lives in stable memory, so there is nothing to save in
+// pre_upgrade. post_upgrade brings old records up to the current schema
+// before anything reads them, then repairs the counters.
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    run_migrations();
+    sync_counter(0, USERS.with(|m| m.borrow().last_key_value().map(|(k, _)| k)));
+    sync_counter(1, DEVICES.with(|m| m.borrow().last_key_value().map(|(k, _)| k)));
+    sync_counter(2, CONTRACTS.with(|m| m.borrow().last_key_value().map(|(k, _)| k)));
+    sync_counter(3, REPORTS.with(|m| m.borrow().last_key_value().map(|(k, _)| k)));
+    sync_counter(5, ORDERS.with(|m| m.borrow().last_key_value().map(|(k, _)| k)));
+    sync_counter(6, APPLICATIONS.with(|m| m.borrow().last_key_value().map(|(k, _)| k)));
+    rebuild_certified_tree();
+}
+
+/// Resolves the calling principal to its registered user.
+fn lookup_caller() -> Result<User, String> {
+    let caller = msg_caller();
+    let user_id = PRINCIPALS.with(|p| p.borrow().get(&caller));
+    match user_id {
+        Some(id) => USERS.with(|users| users.borrow().get(&id).map(|v| v.clone()))
+            .ok_or_else(|| "User not found".to_string()),
+        None => Err("Caller is not a registered user".to_string())
+    }
+}
+
+/// Like `lookup_caller`, but refuses suspended users.
+fn caller_user() -> Result<User, String> {
+    let user = lookup_caller()?;
+    if user.suspended {
+        return Err("This account is suspended".to_string());
+    }
+    Ok(user)
+}
+
+/// Canister controllers are always admins, so the first admin does not have
+/// to be bootstrapped by hand.
+fn require_admin() -> Result<(), String> {
+    if ic_cdk::api::is_controller(&msg_caller()) {
+        return Ok(());
+    }
+    match caller_user() {
+        Ok(user) if user.has_role(&Role::Admin) => Ok(()),
+        _ => Err("Only admins can perform this action".to_string())
+    }
+}
+
+fn settings() -> Settings {
+    SETTINGS.with(|s| s.borrow().get().clone())
+}
+
+fn listing_policy() -> ListingPolicy {
+    LISTING_POLICY.with(|p| p.borrow().get().clone())
+}
+
+/// --------- USER APIS ----------
+#[ic_cdk::update]
+fn add_user(name: String, email: String, role: Role) -> Result<u64, String> {
+    let caller = msg_caller();
+    if caller == Principal::anonymous() {
+        return Err("Anonymous callers cannot register".to_string());
+    }
+    if PRINCIPALS.with(|p| p.borrow().contains_key(&caller)) {
+        return Err("This principal is already registered".to_string());
+    }
+    if role == Role::Admin && !ic_cdk::api::is_controller(&caller) {
+        return Err("Only canister controllers can register as admin".to_string());
+    }
+    if role == Role::Specialist {
+        return Err("Specialists must apply for accreditation".to_string());
+    }
+    let id = next_id(0);
+    let mut user = User { id, principal: caller, name, email, roles: vec![], suspended: false };
+    user.grant(role, caller);
+    USERS.with(|users| { users.borrow_mut().insert(id, user); });
+    PRINCIPALS.with(|p| { p.borrow_mut().insert(caller, id); });
+    Ok(id)
+}
+
+#[ic_cdk::query]
+fn get_user(id: u64) -> Option<User> {
+    USERS.with(|users| users.borrow().get(&id).map(|v| v.clone()))
+}
+
+#[ic_cdk::query]
+fn get_current_user() -> Option<User> {
+    lookup_caller().ok()
+}
+
+#[ic_cdk::update]
+fn update_user(name: String, email: String) -> Result<(), String> {
+    let r = caller_user()?;
+    USERS.with(|users| users.borrow_mut().insert(r.id, User { name, email, ..r }));
+    Ok(())
+}
+
+/// Lets a user take on the buyer or seller role alongside the roles they
+/// already hold. Specialist and admin roles are only granted by admins.
+#[ic_cdk::update]
+fn claim_role(role: Role) -> Result<(), String> {
+    let user = caller_user()?;
+    if role != Role::Buyer && role != Role::Seller {
+        return Err("Only the buyer and seller roles can be claimed".to_string());
+    }
+    modify_user(user.id, |u| u.grant(role, user.principal))
+}
+
+#[ic_cdk::update]
+fn delete_user() -> Result<(), String> {
+    let r = caller_user()?;
+    USERS.with(|users| users.borrow_mut().remove(&r.id));
+    PRINCIPALS.with(|p| p.borrow_mut().remove(&r.principal));
+    Ok(())
+}
+
+/// --------- ADMIN APIS ----------
+fn modify_user<F: FnOnce(&mut User)>(user_id: u64, change: F) -> Result<(), String> {
+    USERS.with(|users| {
+        let mut users = users.borrow_mut();
+        match users.get(&user_id).map(|v| v.clone()) {
+            Some(mut user) => {
+                change(&mut user);
+                users.insert(user_id, user);
+                Ok(())
+            },
+            None => Err("User not found".to_string())
+        }
+    })
+}
+
+#[ic_cdk::update]
+fn grant_role(user_id: u64, role: Role) -> Result<(), String> {
+    require_admin()?;
+    modify_user(user_id, |user| user.grant(role, msg_caller()))
+}
+
+#[ic_cdk::update]
+fn revoke_role(user_id: u64, role: Role) -> Result<(), String> {
+    require_admin()?;
+    modify_user(user_id, |user| user.roles.retain(|g| g.role != role))
+}
+
+#[ic_cdk::update]
+fn suspend_user(user_id: u64) -> Result<(), String> {
+    require_admin()?;
+    modify_user(user_id, |user| user.suspended = true)
+}
+
+#[ic_cdk::update]
+fn reinstate_user(user_id: u64) -> Result<(), String> {
+    require_admin()?;
+    modify_user(user_id, |user| user.suspended = false)
+}
+
+/// Binds a user to the principal they sign in with, replacing the previous
+/// one. Used for users migrated from before principals were tracked and for
+/// users who lost access to their identity.
+#[ic_cdk::update]
+fn link_user_principal(user_id: u64, principal: Principal) -> Result<(), String> {
+    require_admin()?;
+    if principal == Principal::anonymous() {
+        return Err("Users cannot be linked to the anonymous principal".to_string());
+    }
+    if let Some(owner) = PRINCIPALS.with(|p| p.borrow().get(&principal)) {
+        return Err(format!("This principal is already registered to user {}", owner));
+    }
+    let user = get_user(user_id).ok_or_else(|| "User not found".to_string())?;
+    PRINCIPALS.with(|p| {
+        let mut principals = p.borrow_mut();
+        if principals.get(&user.principal) == Some(user_id) {
+            principals.remove(&user.principal);
+        }
+        principals.insert(principal, user_id);
+    });
+    modify_user(user_id, |u| u.principal = principal)
+}
+
+#[ic_cdk::query]
+fn get_settings() -> Settings {
+    settings()
+}
+
+#[ic_cdk::update]
+fn update_settings(new_settings: Settings) -> Result<(), String> {
+    require_admin()?;
+    if new_settings.ecdsa_key_name.trim().is_empty() {
+        return Err("ecdsa_key_name cannot be empty".to_string());
+    }
+    if new_settings.platform_fee_bps > 10_000 {
+        return Err("platform_fee_bps cannot exceed 10000 (100%)".to_string());
+    }
+    if new_settings.max_devices_per_seller == 0 {
+        return Err("max_devices_per_seller must be at least 1".to_string());
+    }
+    if new_settings.max_cart_items == 0 {
+        return Err("max_cart_items must be at least 1".to_string());
+    }
+    SETTINGS.with(|s| s.borrow_mut().set(new_settings))
+        .map(|_| ())
+        .map_err(|e| format!("Failed to store settings: {:?}", e))
+}
+
+#[ic_cdk::query]
+fn get_listing_policy() -> ListingPolicy {
+    listing_policy()
+}
+
+#[ic_cdk::update]
+fn update_listing_policy(policy: ListingPolicy) -> Result<(), String> {
+    require_admin()?;
+    LISTING_POLICY.with(|p| p.borrow_mut().set(policy))
+        .map(|_| ())
+        .map_err(|e| format!("Failed to store the listing policy: {:?}", e))
+}
+
+/// --------- ACCREDITATION APIS ----------
+#[ic_cdk::update]
+fn apply_for_accreditation(credentials: String) -> Result<u64, String> {
+    let user = caller_user()?;
+    if credentials.trim().is_empty() {
+        return Err("Credentials are required".to_string());
+    }
+    let pending = APPLICATIONS.with(|a| a.borrow().iter()
+        .any(|(_, app)| app.user_id == user.id && app.status == ApplicationStatus::Pending));
+    if pending {
+        return Err("An application is already pending review".to_string());
+    }
+    let id = next_id(6);
+    let application = SpecialistApplication {
+        id,
+        user_id: user.id,
+        credentials,
+        submitted_at: time(),
+        status: ApplicationStatus::Pending,
+        reviewed_by: None,
+        reviewed_at: None,
+        review_note: String::new(),
+    };
+    APPLICATIONS.with(|a| a.borrow_mut().insert(id, application));
+    Ok(id)
+}
+
+#[ic_cdk::query]
+fn list_pending_applications() -> Result<Vec<SpecialistApplication>, String> {
+    require_admin()?;
+    Ok(APPLICATIONS.with(|a| a.borrow().iter()
+        .filter_map(|(_, app)| if app.status == ApplicationStatus::Pending { Some(app.clone()) } else { None })
+        .collect()))
+}
+
+#[ic_cdk::query]
+fn get_application(application_id: u64) -> Option<SpecialistApplication> {
+    APPLICATIONS.with(|a| a.borrow().get(&application_id).map(|v| v.clone()))
+}
+
+fn review_application(application_id: u64, status: ApplicationStatus, note: String) -> Result<SpecialistApplication, String> {
+    require_admin()?;
+    APPLICATIONS.with(|a| {
+        let mut applications = a.borrow_mut();
+        match applications.get(&application_id).map(|v| v.clone()) {
+            Some(mut app) if app.status == ApplicationStatus::Pending => {
+                app.status = status;
+                app.reviewed_by = Some(msg_caller());
+                app.reviewed_at = Some(time());
+                app.review_note = note;
+                applications.insert(application_id, app.clone());
+                Ok(app)
+            },
+            Some(_) => Err("Application has already been reviewed".to_string()),
+            None => Err("Application not found".to_string())
+        }
+    })
 }
 
-#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
-pub struct User {
-    id: u64,
-    name: String,
-    email: String,
-    role: Role,
+#[ic_cdk::update]
+fn approve_application(application_id: u64, valid_days: u32) -> Result<(), String> {
+    require_admin()?;
+    if valid_days == 0 {
+        return Err("Accreditation must be valid for at least one day".to_string());
+    }
+    // Check the applicant first so a deleted account does not leave an
+    // approved application and an accreditation behind.
+    let applicant = get_application(application_id).map(|a| a.user_id)
+        .ok_or_else(|| "Application not found".to_string())?;
+    if get_user(applicant).is_none() {
+        return Err("The applicant's account no longer exists".to_string());
+    }
+    let app = review_application(application_id, ApplicationStatus::Approved, String::new())?;
+    let now = time();
+    let one_day_ns: u64 = 24 * 60 * 60 * 1_000_000_000;
+    let accreditation = Accreditation {
+        user_id: app.user_id,
+        application_id,
+        granted_by: msg_caller(),
+        granted_at: now,
+        expires_at: now.saturating_add((valid_days as u64).saturating_mul(one_day_ns)),
+    };
+    ACCREDITATIONS.with(|a| a.borrow_mut().insert(app.user_id, accreditation));
+    modify_user(app.user_id, |user| user.grant(Role::Specialist, msg_caller()))
 }
 
-impl Storable for User {
-    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(bincode::serialize(self).unwrap()) }
-    fn from_bytes(bytes: Cow<[u8]>) -> Self { bincode::deserialize(&bytes).unwrap() }
-    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+#[ic_cdk::update]
+fn reject_application(application_id: u64, note: String) -> Result<(), String> {
+    review_application(application_id, ApplicationStatus::Rejected, note).map(|_| ())
 }
 
-/// --------- DEVICE STRUCT ----------
-#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
-pub struct Device {
-    id: u64,
-    user_id: u64,
-    name: String,
-    specs: String,
+#[ic_cdk::update]
+fn revoke_accreditation(user_id: u64) -> Result<(), String> {
+    require_admin()?;
+    match ACCREDITATIONS.with(|a| a.borrow_mut().remove(&user_id)) {
+        Some(_) => Ok(()),
+        None => Err("User is not accredited".to_string())
+    }
 }
 
-impl Storable for Device {
-    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(bincode::serialize(self).unwrap()) }
-    fn from_bytes(bytes: Cow<[u8]>) -> Self { bincode::deserialize(&bytes).unwrap() }
-    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+#[ic_cdk::query]
+fn get_accreditation(user_id: u64) -> Option<Accreditation> {
+    ACCREDITATIONS.with(|a| a.borrow().get(&user_id).map(|v| v.clone()))
 }
 
-// اضفت price_usd عشان check_device_in_storage يشتغل
-impl Device {
-    fn price_usd(&self) -> u32 {
-        500 // قيمة افتراضية مؤقتة
+/// Checks that a specialist holds an accreditation that has not expired.
+fn require_accreditation(user: &User) -> Result<(), String> {
+    match get_accreditation(user.id) {
+        Some(a) if a.expires_at > time() => Ok(()),
+        Some(_) => Err("Specialist accreditation has expired".to_string()),
+        None => Err("Specialist is not accredited".to_string())
     }
 }
 
-/// --------- WARRANTY CONTRACT STRUCT ----------
-#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
-pub struct WarrantyContract {
-    id: u64,
-    seller_id: u64,
-    buyer_id: u64,
-    device_id: u64,
-    warranty_months: u32,
-    expiry_date: u64,
+/// --------- IDENTIFIER REGISTRY ----------
+/// Checks a 15-digit IMEI against its Luhn check digit.
+fn is_valid_imei(imei: &str) -> bool {
+    if imei.len() != 15 || !imei.chars().all(|c| c.is_ascii_digit()) {
+        return false;
+    }
+    let sum: u32 = imei.chars().rev().enumerate().map(|(i, c)| {
+        let digit = c.to_digit(10).unwrap_or(0);
+        if i % 2 == 1 {
+            let doubled = digit * 2;
+            if doubled > 9 { doubled - 9 } else { doubled }
+        } else {
+            digit
+        }
+    }).sum();
+    sum.is_multiple_of(10)
 }
 
-impl Storable for WarrantyContract {
-    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(bincode::serialize(self).unwrap()) }
-    fn from_bytes(bytes: Cow<[u8]>) -> Self { bincode::deserialize(&bytes).unwrap() }
-    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+fn normalize_imei(imei: &str) -> String {
+    imei.chars().filter(|c| !c.is_whitespace() && *c != '-').collect()
 }
 
-/// --------- REPORT STRUCT ----------
-#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
-pub struct Report {
-    id: u64,
-    device_id: u64,
-    specialist_name: String,
-    notes: String,
-    timestamp: u64,
+fn normalize_serial(serial: &str) -> String {
+    serial.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_uppercase()
 }
 
-impl Storable for Report {
-    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(bincode::serialize(self).unwrap()) }
-    fn from_bytes(bytes: Cow<[u8]>) -> Self { bincode::deserialize(&bytes).unwrap() }
-    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+fn imei_key(imei: &str) -> String {
+    format!("imei:{}", normalize_imei(imei))
 }
 
-/// --------- CART STRUCT ----------
-#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
-pub struct Cart {
-    user_id: u64,
-    device_ids: Vec<u64>,
+fn serial_key(serial: &str) -> String {
+    format!("sn:{}", normalize_serial(serial))
 }
 
-impl Storable for Cart {
-    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(bincode::serialize(self).unwrap()) }
-    fn from_bytes(bytes: Cow<[u8]>) -> Self { bincode::deserialize(&bytes).unwrap() }
-    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+/// Registry keys of a device: its serial number and, for phones, its IMEI.
+fn identifier_keys(identity: &DeviceIdentity) -> Vec<String> {
+    let mut keys = Vec::new();
+    if !identity.serial_number.trim().is_empty() {
+        keys.push(serial_key(&identity.serial_number));
+    }
+    if let Some(imei) = identity.imei.as_deref().filter(|imei| !imei.trim().is_empty()) {
+        keys.push(imei_key(imei));
+    }
+    keys
 }
 
-/// --------- ORDER STRUCT ----------
-#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
-pub struct Order {
-    id: u64,
-    buyer_id: u64,
-    seller_id: u64,
-    device_id: u64,
-    timestamp: u64,
+/// Validates the identifiers of a new listing by `owner_id` and rejects them
+/// when another device already uses them.
+fn check_identifiers(identity: &DeviceIdentity, owner_id: u64) -> Result<(), String> {
+    if identity.serial_number.trim().is_empty() {
+        return Err("identity.serial_number is required".to_string());
+    }
+    if let Some(imei) = identity.imei.as_deref().filter(|imei| !imei.trim().is_empty()) {
+        if !is_valid_imei(&normalize_imei(imei)) {
+            return Err(format!("Invalid IMEI '{}'", imei));
+        }
+    }
+    check_identifiers_free(identity, None, owner_id)
 }
 
-impl Storable for Order {
-    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(bincode::serialize(self).unwrap()) }
-    fn from_bytes(bytes: Cow<[u8]>) -> Self { bincode::deserialize(&bytes).unwrap() }
-    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+/// Rejects identifiers held by a device other than `device_id`. A physical
+/// device keeps one record for its whole history: once sold or archived it
+/// is relisted through `set_device_status` by its current owner, never
+/// registered again.
+fn check_identifiers_free(identity: &DeviceIdentity, device_id: Option<u64>, owner_id: u64) -> Result<(), String> {
+    for key in identifier_keys(identity) {
+        let holder = IDENTIFIERS.with(|i| i.borrow().get(&key))
+            .filter(|existing| Some(*existing) != device_id)
+            .and_then(get_device);
+        if let Some(existing) = holder {
+            if existing.status.is_active() {
+                return Err(format!("This device is already listed as device {}", existing.id));
+            }
+            if existing.user_id != owner_id {
+                return Err(format!("This device is registered to another owner as device {}", existing.id));
+            }
+            return Err(format!("You already own this device as device {}; relist it with set_device_status", existing.id));
+        }
+    }
+    Ok(())
 }
 
-/// --------- STORAGE ----------
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+fn register_identifiers(device: &Device) {
+    IDENTIFIERS.with(|i| {
+        let mut index = i.borrow_mut();
+        for key in identifier_keys(&device.identity) {
+            index.insert(key, device.id);
+        }
+    });
+}
 
-    static USERS: RefCell<StableBTreeMap<u64, User, Memory>> = RefCell::new(
-        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))))
-    );
+/// Looks a device up by the IMEI or serial number printed on it.
+#[ic_cdk::query]
+fn find_device_by_identifier(identifier: String) -> Option<Device> {
+    let device_id = IDENTIFIERS.with(|i| {
+        let index = i.borrow();
+        index.get(&imei_key(&identifier)).or_else(|| index.get(&serial_key(&identifier)))
+    })?;
+    DEVICES.with(|devices| devices.borrow().get(&device_id).map(|v| v.clone()))
+}
 
-    static DEVICES: RefCell<StableBTreeMap<u64, Device, Memory>> = RefCell::new(
-        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))))
-    );
+/// --------- BLACKLIST APIS ----------
+/// Registry keys covered by a report: every identifier of the matching device,
+/// or the bare identifier when it does not belong to a listed device.
+fn blacklist_keys(identifier: &str) -> (Option<u64>, Vec<String>) {
+    if let Some(device) = find_device_by_identifier(identifier.to_string()) {
+        return (Some(device.id), identifier_keys(&device.identity));
+    }
+    let imei = normalize_imei(identifier);
+    if is_valid_imei(&imei) {
+        (None, vec![imei_key(&imei)])
+    } else {
+        (None, vec![serial_key(identifier)])
+    }
+}
 
-    static CONTRACTS: RefCell<StableBTreeMap<u64, WarrantyContract, Memory>> = RefCell::new(
-        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))))
-    );
+fn active_blacklist_entry(keys: &[String]) -> Option<BlacklistEntry> {
+    let entry_id = ACTIVE_BLACKLIST.with(|a| {
+        let active = a.borrow();
+        keys.iter().find_map(|key| active.get(key))
+    })?;
+    BLACKLIST.with(|b| b.borrow().get(&entry_id).map(|v| v.clone()))
+}
 
-    static REPORTS: RefCell<StableBTreeMap<u64, Report, Memory>> = RefCell::new(
-        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))))
-    );
+/// Fails when any identifier of the device has been reported lost or stolen.
+fn check_not_blacklisted(identity: &DeviceIdentity) -> Result<(), String> {
+    match active_blacklist_entry(&identifier_keys(identity)) {
+        Some(entry) => {
+            let reason = match entry.reason { BlacklistReason::Lost => "lost", BlacklistReason::Stolen => "stolen" };
+            Err(format!("Device identifier '{}' has been reported {}", entry.identifier, reason))
+        },
+        None => Ok(())
+    }
+}
 
-    static CARTS: RefCell<StableBTreeMap<u64, Cart, Memory>> = RefCell::new(
-        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))))
-    );
+/// Flags a device as lost or stolen. Owners can flag their own devices;
+/// admins can flag any identifier, listed or not.
+#[ic_cdk::update]
+fn report_lost_or_stolen(identifier: String, reason: BlacklistReason, note: String) -> Result<u64, String> {
+    if identifier.trim().is_empty() {
+        return Err("Identifier is required".to_string());
+    }
+    let (device_id, keys) = blacklist_keys(&identifier);
+    if require_admin().is_err() {
+        let user = caller_user()?;
+        let owner = device_id.and_then(get_device).map(|d| d.user_id);
+        if owner != Some(user.id) {
+            return Err("Only the device owner or an admin can report it lost or stolen".to_string());
+        }
+    }
+    if active_blacklist_entry(&keys).is_some() {
+        return Err("This device is already blacklisted".to_string());
+    }
+    let id = next_id(8);
+    let entry = BlacklistEntry {
+        id,
+        identifier,
+        keys: keys.clone(),
+        device_id,
+        reason,
+        note,
+        reported_by: msg_caller(),
+        reported_at: time(),
+        cleared_by: None,
+        cleared_at: None,
+    };
+    BLACKLIST.with(|b| b.borrow_mut().insert(id, entry));
+    ACTIVE_BLACKLIST.with(|a| {
+        let mut active = a.borrow_mut();
+        for key in keys {
+            active.insert(key, id);
+        }
+    });
+    Ok(id)
+}
 
-    static ORDERS: RefCell<StableBTreeMap<u64, Order, Memory>> = RefCell::new(
-        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))))
-    );
+/// Clears the report currently in force. Allowed for admins and for whoever
+/// filed the report, e.g. an owner who recovered the device.
+#[ic_cdk::update]
+fn clear_blacklist_entry(identifier: String) -> Result<(), String> {
+    let (_, keys) = blacklist_keys(&identifier);
+    let mut entry = active_blacklist_entry(&keys).ok_or_else(|| "This device is not blacklisted".to_string())?;
+    if entry.reported_by != msg_caller() {
+        require_admin()?;
+    }
+    entry.cleared_by = Some(msg_caller());
+    entry.cleared_at = Some(time());
+    ACTIVE_BLACKLIST.with(|a| {
+        let mut active = a.borrow_mut();
+        for key in &entry.keys {
+            active.remove(key);
+        }
+    });
+    BLACKLIST.with(|b| b.borrow_mut().insert(entry.id, entry));
+    Ok(())
+}
 
-    static COUNTERS: RefCell<[u64; 6]> = RefCell::new([0; 6]); // user, device, contract, report, cart, order
+#[ic_cdk::query]
+fn is_blacklisted(identifier: String) -> bool {
+    let (_, keys) = blacklist_keys(&identifier);
+    active_blacklist_entry(&keys).is_some()
 }
 
-fn next_id(index: usize) -> u64 {
-    COUNTERS.with(|c| {
-        let mut counters = c.borrow_mut();
-        counters[index] += 1;
-        counters[index]
-    })
+/// Every report ever filed against the identifier, including cleared ones.
+#[ic_cdk::query]
+fn get_blacklist_history(identifier: String) -> Vec<BlacklistEntry> {
+    let (_, keys) = blacklist_keys(&identifier);
+    BLACKLIST.with(|b| b.borrow().iter()
+        .filter_map(|(_, e)| if e.keys.iter().any(|k| keys.contains(k)) { Some(e.clone()) } else { None })
+        .collect())
 }
 
-/// --------- USER APIS ----------
+/// --------- DEVICE APIS ----------
 #[ic_cdk::update]
-fn add_user(name: String, email: String, role: Role) -> u64 {
-    let id = next_id(0);
-    let user = User { id, name, email, role };
-    USERS.with(|users| { users.borrow_mut().insert(id, user); });
-    id
+fn add_device(input: DeviceInput) -> Result<u64, String> {
+    let user = caller_user()?;
+    input.price.validate()?;
+    let device = Device {
+        id: 0,
+        user_id: user.id,
+        name: input.name,
+        specs: input.specs,
+        brand: input.brand,
+        model: input.model,
+        year: input.year,
+        identity: input.identity,
+        hardware: input.hardware,
+        inspection_summary: input.inspection_summary,
+        blockchain: None,
+        price: Some(input.price),
+        status: DeviceStatus::Listed,
+    };
+    create_device(&user, device)
+}
+
+/// Lists `device` for `seller`, assigning its id. Shared by `add_device` and
+/// the bulk import so both apply the same checks.
+fn create_device(seller: &User, mut device: Device) -> Result<u64, String> {
+    if !seller.has_role(&Role::Seller) {
+        return Err("Only sellers can add devices".to_string());
+    }
+    check_listing_limit(seller)?;
+    check_identifiers(&device.identity, seller.id)?;
+    check_not_blacklisted(&device.identity)?;
+    check_listing_policy(&device.identity)?;
+    let id = next_id(1);
+    device.id = id;
+    device.user_id = seller.id;
+    let price = device.price.clone();
+    register_identifiers(&device);
+    index_device_text(&device);
+    DEVICES.with(|devices| { devices.borrow_mut().insert(id, device); });
+    index_insert(&DEVICES_BY_OWNER, seller.id, id);
+    append_ownership(id, None, seller.id, OwnershipEvent::Registered, time());
+    if let Some(price) = price {
+        record_price_change(id, None, price, seller.id);
+    }
+    Ok(id)
+}
+
+fn append_ownership(device_id: u64, from_user_id: Option<u64>, to_user_id: u64, event: OwnershipEvent, recorded_at: u64) {
+    let id = next_id(9);
+    let record = OwnershipRecord { id, device_id, from_user_id, to_user_id, event, recorded_at };
+    OWNERSHIP.with(|o| o.borrow_mut().insert((device_id, id), record));
 }
 
+/// The device's ownership chain, oldest first.
 #[ic_cdk::query]
-fn get_user(id: u64) -> Option<User> {
-    USERS.with(|users| users.borrow().get(&id).map(|v| v.clone()))
+fn get_device_provenance(device_id: u64) -> Vec<OwnershipRecord> {
+    OWNERSHIP.with(|o| o.borrow().range((device_id, 0)..=(device_id, u64::MAX)).map(|(_, r)| r.clone()).collect())
 }
 
+/// Hands one of the caller's devices to another user outside the marketplace.
+/// When `contract_id` is given it must be a warranty contract from the caller
+/// to the recipient for this device. The device ends up sold to the recipient.
 #[ic_cdk::update]
-fn update_user(requester_id: u64, name: String, email: String, role: Role) -> Result<(), String> {
-    let requester = USERS.with(|u| u.borrow().get(&requester_id).map(|v| v.clone()));
-    match requester {
-        Some(r) => {
-            USERS.with(|users| users.borrow_mut().insert(r.id, User { id: r.id, name, email, role }));
-            Ok(())
-        },
-        _ => Err("Only the user itself can update its profile".to_string())
+fn transfer_device(device_id: u64, to_user_id: u64, contract_id: Option<u64>, note: String) -> Result<(), String> {
+    let user = caller_user()?;
+    let mut device = get_device(device_id).ok_or_else(|| "Device not found".to_string())?;
+    if device.user_id != user.id {
+        return Err("Only the owner can transfer this device".to_string());
+    }
+    if device.status == DeviceStatus::Reserved {
+        return Err("The device is reserved by an order; cancel the order instead".to_string());
     }
+    if to_user_id == user.id {
+        return Err("The device already belongs to you".to_string());
+    }
+    if get_user(to_user_id).is_none() {
+        return Err("Recipient not found".to_string());
+    }
+    if let Some(contract_id) = contract_id {
+        let contract = get_contract(contract_id).ok_or_else(|| "Contract not found".to_string())?;
+        if contract.device_id != device_id || contract.seller_id != user.id || contract.buyer_id != to_user_id {
+            return Err("The contract does not cover this transfer".to_string());
+        }
+    }
+    check_not_blacklisted(&device.identity)?;
+    device.user_id = to_user_id;
+    device.status = DeviceStatus::Sold;
+    DEVICES.with(|devices| devices.borrow_mut().insert(device_id, device));
+    reindex_owner(device_id, user.id, to_user_id);
+    append_ownership(device_id, Some(user.id), to_user_id, OwnershipEvent::Transfer { contract_id, note }, time());
+    Ok(())
 }
 
-#[ic_cdk::update]
-fn delete_user(requester_id: u64) -> Result<(), String> {
-    let requester = USERS.with(|u| u.borrow().get(&requester_id).map(|v| v.clone()));
-    match requester {
-        Some(r) => {
-            USERS.with(|users| users.borrow_mut().remove(&r.id));
-            Ok(())
-        },
-        _ => Err("Only the user itself can delete its profile".to_string())
+/// Counts the seller's active devices against `max_devices_per_seller`.
+fn check_listing_limit(seller: &User) -> Result<(), String> {
+    let active = index_ids(&DEVICES_BY_OWNER, seller.id).into_iter()
+        .filter_map(get_device)
+        .filter(|d| d.status.is_active())
+        .count();
+    if active >= settings().max_devices_per_seller as usize {
+        return Err("Seller has reached the device listing limit".to_string());
     }
+    Ok(())
+}
+
+fn record_price_change(device_id: u64, old_price: Option<Price>, new_price: Price, changed_by: u64) {
+    let id = next_id(7);
+    let change = PriceChange { id, device_id, old_price, new_price, changed_by, changed_at: time() };
+    PRICE_HISTORY.with(|h| h.borrow_mut().insert((device_id, id), change));
 }
 
-/// --------- DEVICE APIS ----------
 #[ic_cdk::update]
-fn add_device(user_id: u64, name: String, specs: String) -> Result<u64, String> {
-    let user_opt = USERS.with(|users| users.borrow().get(&user_id).map(|v| v.clone()));
-    match user_opt {
-        Some(user) => {
-            if user.role == Role::Seller {
-                let id = next_id(1);
-                let device = Device { id, user_id, name, specs };
-                DEVICES.with(|devices| { devices.borrow_mut().insert(id, device); });
-                Ok(id)
-            } else {
-                Err("Only sellers can add devices".to_string())
+fn set_device_price(device_id: u64, price: Price) -> Result<(), String> {
+    let user = caller_user()?;
+    price.validate()?;
+    let device_opt = DEVICES.with(|devices| devices.borrow().get(&device_id).map(|v| v.clone()));
+    match device_opt {
+        Some(mut device) => {
+            if device.user_id != user.id {
+                return Err("Only the owner seller can change the price".to_string());
+            }
+            if device.status == DeviceStatus::Reserved {
+                return Err("The price of a reserved device cannot change".to_string());
             }
+            if device.price.as_ref() == Some(&price) {
+                return Ok(());
+            }
+            let old_price = device.price.replace(price.clone());
+            DEVICES.with(|devices| devices.borrow_mut().insert(device_id, device));
+            record_price_change(device_id, old_price, price, user.id);
+            Ok(())
         },
-        None => Err("User not found".to_string())
+        None => Err("Device not found".to_string())
     }
 }
 
+#[ic_cdk::query]
+fn get_price_history(device_id: u64) -> Vec<PriceChange> {
+    PRICE_HISTORY.with(|h| h.borrow().range((device_id, 0)..=(device_id, u64::MAX)).map(|(_, c)| c.clone()).collect())
+}
+
+/// Moves one of the caller's devices to `status`. Owners can switch between
+/// Draft, Listed and Archived, and relist a device they bought; Reserved and
+/// Sold are only reached through orders.
 #[ic_cdk::update]
-fn delete_device(user_id: u64, device_id: u64) -> Result<(), String> {
+fn set_device_status(device_id: u64, status: DeviceStatus) -> Result<(), String> {
+    let user = caller_user()?;
     let device_opt = DEVICES.with(|devices| devices.borrow().get(&device_id).map(|v| v.clone()));
     match device_opt {
-        Some(device) => {
-            if device.user_id == user_id {
-                DEVICES.with(|devices| devices.borrow_mut().remove(&device_id));
-                Ok(())
-            } else {
-                Err("Only the owner seller can delete this device".to_string())
+        Some(mut device) => {
+            if device.user_id != user.id {
+                return Err("Only the owner can change the status of this device".to_string());
             }
+            if device.status == status {
+                return Ok(());
+            }
+            match (device.status, status) {
+                (DeviceStatus::Reserved, _) => return Err("The device is reserved by an order; cancel the order instead".to_string()),
+                (_, DeviceStatus::Reserved) | (_, DeviceStatus::Sold) => return Err("Devices become reserved or sold only through orders".to_string()),
+                _ => {},
+            }
+            if status == DeviceStatus::Listed {
+                if !user.has_role(&Role::Seller) {
+                    return Err("Only sellers can list devices".to_string());
+                }
+                if device.price.is_none() {
+                    return Err("Set a price before listing the device".to_string());
+                }
+                check_not_blacklisted(&device.identity)?;
+                check_listing_policy(&device.identity)?;
+            }
+            if status.is_active() && !device.status.is_active() {
+                check_listing_limit(&user)?;
+                check_identifiers_free(&device.identity, Some(device.id), user.id)?;
+                register_identifiers(&device);
+            }
+            device.status = status;
+            DEVICES.with(|devices| devices.borrow_mut().insert(device_id, device));
+            Ok(())
         },
         None => Err("Device not found".to_string())
     }
 }
 
+/// Archives the device. Devices are never removed so their reports,
+/// contracts and orders stay queryable.
+#[ic_cdk::update]
+fn delete_device(device_id: u64) -> Result<(), String> {
+    set_device_status(device_id, DeviceStatus::Archived)
+}
+
 #[ic_cdk::query]
 fn get_device(device_id: u64) -> Option<Device> {
     DEVICES.with(|devices| devices.borrow().get(&device_id).map(|v| v.clone()))
 }
 
+/// Devices currently for sale.
 #[ic_cdk::query]
-fn list_devices() -> Vec<Device> {
-    DEVICES.with(|devices| devices.borrow().iter().map(|(_, d)| d.clone()).collect())
+fn list_devices() -> Vec<DeviceListing> {
+    let policy = listing_policy();
+    DEVICES.with(|devices| devices.borrow().iter().filter_map(|(_, d)| if is_for_sale(&policy, &d) { Some(to_listing(&policy, d)) } else { None }).collect())
 }
 
+/// Every device the user owns, whatever its status.
 #[ic_cdk::query]
-fn list_user_devices(user_id: u64) -> Vec<Device> {
-    DEVICES.with(|devices| devices.borrow().iter().filter_map(|(_, d)| if d.user_id == user_id { Some(d.clone()) } else { None }).collect())
+fn list_user_devices(user_id: u64) -> Vec<DeviceListing> {
+    let policy = listing_policy();
+    index_ids(&DEVICES_BY_OWNER, user_id).into_iter()
+        .filter_map(get_device)
+        .map(|d| to_listing(&policy, d))
+        .collect()
+}
+
+fn to_listing(policy: &ListingPolicy, device: Device) -> DeviceListing {
+    let warnings = policy.evaluate(&device.identity).into_iter()
+        .filter_map(|(warning, action)| if action == LockAction::Allow { None } else { Some(warning) })
+        .collect();
+    DeviceListing { device, warnings }
+}
+
+/// Listed devices the current policy does not block. The policy can change
+/// after a device was listed, so buyers only see and buy devices that pass it.
+fn is_for_sale(policy: &ListingPolicy, device: &Device) -> bool {
+    device.status == DeviceStatus::Listed && !policy.blocks(&device.identity)
+}
+
+/// Rejects listings and purchases of devices with a lock condition the
+/// policy blocks.
+fn check_listing_policy(identity: &DeviceIdentity) -> Result<(), String> {
+    let blocked: Vec<String> = listing_policy().evaluate(identity).into_iter()
+        .filter_map(|(warning, action)| if action == LockAction::Block { Some(format!("{:?}", warning)) } else { None })
+        .collect();
+    if blocked.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Devices with these conditions cannot be listed or sold: {}", blocked.join(", ")))
+    }
 }
 
 /// --------- CONTRACT APIS ----------
 #[ic_cdk::update]
-fn create_contract(seller_id: u64, buyer_id: u64, device_id: u64, warranty_months: u32) -> Result<u64, String> {
+fn create_contract(buyer_id: u64, device_id: u64, warranty_months: u32) -> Result<u64, String> {
+    let seller = caller_user()?;
     let device_opt = DEVICES.with(|d| d.borrow().get(&device_id).map(|v| v.clone()));
     match device_opt {
         Some(device) => {
-            if device.user_id != seller_id {
+            if device.user_id != seller.id && !sold_to(seller.id, buyer_id, device_id) {
                 return Err("Only the seller owner of the device can create a contract".to_string());
             }
             let now = time();
@@ -266,14 +2698,23 @@ fn create_contract(seller_id: u64, buyer_id: u64, device_id: u64, warranty_month
             let expiry_date = now + (warranty_months as u64 * one_month_ns);
 
             let id = next_id(2);
-            let contract = WarrantyContract { id, seller_id, buyer_id, device_id, warranty_months, expiry_date };
+            let contract = WarrantyContract { id, seller_id: seller.id, buyer_id, device_id, warranty_months, expiry_date };
             CONTRACTS.with(|contracts| contracts.borrow_mut().insert(id, contract));
+            index_insert(&CONTRACTS_BY_DEVICE, device_id, id);
             Ok(id)
         },
         None => Err("Device not found".to_string())
     }
 }
 
+/// Whether `seller_id` sold or reserved the device to `buyer_id` through an
+/// order, so warranties can still be issued after ownership moves.
+fn sold_to(seller_id: u64, buyer_id: u64, device_id: u64) -> bool {
+    list_device_orders(device_id).iter().any(|o| {
+        o.seller_id == seller_id && o.buyer_id == buyer_id && o.status != OrderStatus::Cancelled
+    })
+}
+
 #[ic_cdk::query]
 fn get_contract(contract_id: u64) -> Option<WarrantyContract> {
     CONTRACTS.with(|contracts| contracts.borrow().get(&contract_id).map(|v| v.clone()))
@@ -286,34 +2727,139 @@ fn list_contracts() -> Vec<WarrantyContract> {
 
 #[ic_cdk::update]
 fn delete_contract(contract_id: u64) -> Result<(), String> {
-    let existed = CONTRACTS.with(|contracts| contracts.borrow_mut().remove(&contract_id));
-    match existed {
-        Some(_) => Ok(()),
+    let contract_opt = CONTRACTS.with(|contracts| contracts.borrow().get(&contract_id).map(|v| v.clone()));
+    match contract_opt {
+        Some(contract) => {
+            let is_seller = caller_user().map(|u| u.id == contract.seller_id).unwrap_or(false);
+            if is_seller || require_admin().is_ok() {
+                CONTRACTS.with(|contracts| contracts.borrow_mut().remove(&contract_id));
+                index_remove(&CONTRACTS_BY_DEVICE, contract.device_id, contract_id);
+                Ok(())
+            } else {
+                Err("Only the seller of the contract or an admin can delete it".to_string())
+            }
+        },
         None => Err("Contract not found".to_string())
     }
 }
 
-/// --------- REPORT APIS ----------
+/// --------- CHECKLIST TEMPLATE APIS ----------
+/// Replaces the checklist of `category` and returns its new version.
 #[ic_cdk::update]
-fn add_report(user_id: u64, device_id: u64, specialist_name: String, notes: String) -> Result<u64, String> {
-    let user_opt = USERS.with(|users| users.borrow().get(&user_id).map(|v| v.clone()));
-    match user_opt {
-        Some(user) => {
-            if user.role != Role::Specialist {
-                return Err("Only specialists can add reports".to_string());
-            }
-            let device_opt = DEVICES.with(|d| d.borrow().get(&device_id).map(|v| v.clone()));
-            if device_opt.is_none() {
-                return Err("Device not found".to_string());
+fn set_checklist_template(category: DeviceCategory, items: Vec<ChecklistItem>) -> Result<u32, String> {
+    require_admin()?;
+    if items.is_empty() {
+        return Err("A checklist needs at least one item".to_string());
+    }
+    let mut keys = std::collections::BTreeSet::new();
+    for item in &items {
+        if item.key.trim().is_empty() {
+            return Err("Checklist item keys cannot be empty".to_string());
+        }
+        if !keys.insert(item.key.as_str()) {
+            return Err(format!("Duplicate checklist item '{}'", item.key));
+        }
+        if let ChecklistValueType::Choice(options) = &item.value_type {
+            if options.is_empty() {
+                return Err(format!("Checklist item '{}' has no options", item.key));
             }
-            let now = time();
-            let id = next_id(3);
-            let report = Report { id, device_id, specialist_name, notes, timestamp: now };
-            REPORTS.with(|reports| reports.borrow_mut().insert(id, report));
-            Ok(id)
+        }
+    }
+    let version = get_checklist_template(category.clone()).map(|t| t.version + 1).unwrap_or(1);
+    let template = ChecklistTemplate { category: category.clone(), version, items, updated_by: msg_caller(), updated_at: time() };
+    CHECKLISTS.with(|c| c.borrow_mut().insert(category.key().to_string(), template));
+    Ok(version)
+}
+
+#[ic_cdk::query]
+fn get_checklist_template(category: DeviceCategory) -> Option<ChecklistTemplate> {
+    CHECKLISTS.with(|c| c.borrow().get(&category.key().to_string()).map(|t| t.clone()))
+}
+
+#[ic_cdk::query]
+fn list_checklist_templates() -> Vec<ChecklistTemplate> {
+    CHECKLISTS.with(|c| c.borrow().iter().map(|(_, t)| t.clone()).collect())
+}
+
+/// Checks `answers` against `template`: every answer must belong to an item
+/// and have its value type, and every required item must be answered.
+fn validate_answers(template: &ChecklistTemplate, answers: &[ChecklistAnswer]) -> Result<(), String> {
+    let mut answered = std::collections::BTreeSet::new();
+    for answer in answers {
+        let item = template.items.iter().find(|i| i.key == answer.key)
+            .ok_or_else(|| format!("'{}' is not on the checklist", answer.key))?;
+        if !answered.insert(answer.key.as_str()) {
+            return Err(format!("'{}' is answered more than once", answer.key));
+        }
+        let valid = match (&item.value_type, &answer.value) {
+            (ChecklistValueType::Bool, ChecklistValue::Bool(_)) => true,
+            (ChecklistValueType::Number, ChecklistValue::Number(n)) => n.is_finite(),
+            (ChecklistValueType::Text, ChecklistValue::Text(_)) => true,
+            (ChecklistValueType::Choice(options), ChecklistValue::Choice(choice)) => options.contains(choice),
+            _ => false,
+        };
+        if !valid {
+            return Err(format!("Invalid value for '{}'", answer.key));
+        }
+    }
+    let missing: Vec<&str> = template.items.iter()
+        .filter(|i| i.required && !answered.contains(i.key.as_str()))
+        .map(|i| i.key.as_str())
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!("Missing required checks: {}", missing.join(", ")));
+    }
+    Ok(())
+}
+
+/// --------- REPORT APIS ----------
+/// Checks report answers for a device of `category` and returns the version of
+/// the checklist they follow. When the category has no template yet the
+/// report is free-form and `answers` must be empty.
+fn check_report_answers(category: DeviceCategory, answers: &[ChecklistAnswer]) -> Result<Option<u32>, String> {
+    match get_checklist_template(category) {
+        Some(template) => {
+            validate_answers(&template, answers)?;
+            Ok(Some(template.version))
         },
-        None => Err("User not found".to_string())
+        None if answers.is_empty() => Ok(None),
+        None => Err("There is no checklist for this device category".to_string()),
+    }
+}
+
+/// Adds an inspection report following the checklist of the device's
+/// category. Devices without hardware sections, such as those migrated from
+/// the first release, have no category and only take free-form reports.
+#[ic_cdk::update]
+fn add_report(device_id: u64, specialist_name: String, notes: String, answers: Vec<ChecklistAnswer>) -> Result<u64, String> {
+    let user = caller_user()?;
+    if !user.has_role(&Role::Specialist) {
+        return Err("Only specialists can add reports".to_string());
+    }
+    require_accreditation(&user)?;
+    let device = get_device(device_id).ok_or_else(|| "Device not found".to_string())?;
+    let template_version = match device.category() {
+        Some(category) => check_report_answers(category, &answers)?,
+        None if answers.is_empty() => None,
+        None => return Err("The device has no hardware sections, so there is no checklist to answer".to_string()),
+    };
+    Ok(create_report(device_id, specialist_name, notes, template_version, answers))
+}
+
+fn create_report(device_id: u64, specialist_name: String, notes: String, template_version: Option<u32>, answers: Vec<ChecklistAnswer>) -> u64 {
+    let now = time();
+    let id = next_id(3);
+    let mut report = Report { id, device_id, specialist_name, notes, timestamp: now, template_version, answers, content_hash: String::new() };
+    report.content_hash = sha256_hex(&canonical_report(&report));
+    if let Some(mut device) = get_device(device_id) {
+        device.blockchain = Some(BlockchainRecord { report_hash: report.content_hash.clone(), timestamp: now.to_string() });
+        DEVICES.with(|d| d.borrow_mut().insert(device_id, device));
     }
+    index_report_text(&report);
+    certify(report_tree_key(id), report_digest(&report));
+    REPORTS.with(|reports| reports.borrow_mut().insert(id, report));
+    index_insert(&REPORTS_BY_DEVICE, device_id, id);
+    id
 }
 
 #[ic_cdk::query]
@@ -321,55 +2867,78 @@ fn get_report(report_id: u64) -> Option<Report> {
     REPORTS.with(|reports| reports.borrow().get(&report_id).map(|v| v.clone()))
 }
 
+/// The canonical JSON document whose SHA-256 is the report's `content_hash`.
+/// Exports handed to buyers should embed this document or its hash.
 #[ic_cdk::query]
-fn list_reports() -> Vec<Report> {
-    REPORTS.with(|reports| reports.borrow().iter().map(|(_, r)| r.clone()).collect())
+fn get_report_document(report_id: u64) -> Option<String> {
+    get_report(report_id).map(|r| String::from_utf8(canonical_report(&r)).unwrap())
 }
 
+/// The report with a certificate and witness for its document hash. Only
+/// works as a query call: the certificate is not available in updates.
 #[ic_cdk::query]
-fn list_device_reports(device_id: u64) -> Vec<Report> {
-    REPORTS.with(|reports| reports.borrow().iter()
-        .filter_map(|(_, r)| if r.device_id == device_id { Some(r.clone()) } else { None })
-        .collect()
-    )
+fn get_certified_report(report_id: u64) -> Result<CertifiedReport, String> {
+    let report = get_report(report_id).ok_or_else(|| "Report not found".to_string())?;
+    let certificate = ic_cdk::api::data_certificate()
+        .ok_or_else(|| "Certificates are only available in query calls".to_string())?;
+    Ok(CertifiedReport {
+        document: String::from_utf8(canonical_report(&report)).unwrap(),
+        witness: certified_witness(&report_tree_key(report_id)),
+        certificate,
+        report,
+    })
 }
 
+/// Checks a report document, or a hex SHA-256 of one, against the hash
+/// stored for `report_id`.
 #[ic_cdk::query]
-fn list_user_reports(user_id: u64) -> Vec<Report> {
-    DEVICES.with(|devices| {
-        let device_map: std::collections::HashMap<u64, u64> = devices.borrow().iter().map(|(id, d)| (id, d.user_id)).collect();
-        REPORTS.with(|reports| {
-            reports.borrow().iter()
-                .filter_map(|(_, r)| {
-                    if let Some(owner_id) = device_map.get(&r.device_id) {
-                        if *owner_id == user_id { return Some(r.clone()); }
-                    }
-                    None
-                })
-                .collect()
-        })
+fn verify_report(report_id: u64, evidence: ReportEvidence) -> Result<ReportVerification, String> {
+    let report = get_report(report_id).ok_or_else(|| "Report not found".to_string())?;
+    let provided_hash = match evidence {
+        ReportEvidence::Document(bytes) => sha256_hex(&bytes),
+        ReportEvidence::Hash(hash) => hash.trim().to_lowercase(),
+    };
+    Ok(ReportVerification {
+        report_id,
+        matches: provided_hash == report.content_hash,
+        stored_hash: report.content_hash,
+        provided_hash,
     })
 }
 
+#[ic_cdk::query]
+fn list_reports() -> Vec<Report> {
+    REPORTS.with(|reports| reports.borrow().iter().map(|(_, r)| r.clone()).collect())
+}
+
+#[ic_cdk::query]
+fn list_device_reports(device_id: u64) -> Vec<Report> {
+    index_ids(&REPORTS_BY_DEVICE, device_id).into_iter().filter_map(get_report).collect()
+}
+
+/// Reports on every device the user currently owns.
+#[ic_cdk::query]
+fn list_user_reports(user_id: u64) -> Vec<Report> {
+    index_ids(&DEVICES_BY_OWNER, user_id).into_iter().flat_map(list_device_reports).collect()
+}
+
 #[ic_cdk::update]
-fn delete_report(user_id: u64, report_id: u64) -> Result<(), String> {
+fn delete_report(report_id: u64) -> Result<(), String> {
+    let user = caller_user()?;
     let report_opt = REPORTS.with(|reports| reports.borrow().get(&report_id).map(|v| v.clone()));
     match report_opt {
         Some(report) => {
             let device_opt = DEVICES.with(|d| d.borrow().get(&report.device_id).map(|v| v.clone()));
             match device_opt {
                 Some(device) => {
-                    let user_opt = USERS.with(|u| u.borrow().get(&user_id).map(|v| v.clone()));
-                    match user_opt {
-                        Some(user) => {
-                            if user.role == Role::Specialist || device.user_id == user_id {
-                                REPORTS.with(|reports| reports.borrow_mut().remove(&report_id));
-                                Ok(())
-                            } else {
-                                Err("Only the specialist or seller owner can delete this report".to_string())
-                            }
-                        },
-                        None => Err("User not found".to_string())
+                    if user.has_role(&Role::Specialist) || device.user_id == user.id {
+                        REPORTS.with(|reports| reports.borrow_mut().remove(&report_id));
+                        index_remove(&REPORTS_BY_DEVICE, report.device_id, report_id);
+                        remove_postings(&report.notes, report.device_id, report_id);
+                        uncertify(&report_tree_key(report_id));
+                        Ok(())
+                    } else {
+                        Err("Only the specialist or seller owner can delete this report".to_string())
                     }
                 },
                 None => Err("Device not found".to_string())
@@ -379,51 +2948,268 @@ fn delete_report(user_id: u64, report_id: u64) -> Result<(), String> {
     }
 }
 
+/// --------- BULK IMPORT ----------
+/// Largest number of inspection records accepted by one import call; bigger
+/// files are sent in several chunks.
+const MAX_IMPORT_BATCH: usize = 50;
+
+/// One entry of `data/devices*.json`, as produced by the inspection tooling.
+#[derive(Deserialize)]
+struct InspectionRecord {
+    device_id: String,
+    category: String,
+    brand: String,
+    model: String,
+    year: u32,
+    identity: DeviceIdentity,
+    cpu: CpuInfo,
+    #[serde(default)]
+    gpu: Option<GpuInfo>,
+    memory: serde_json::Value,
+    #[serde(default)]
+    storage: Option<StorageInfo>,
+    battery: BatteryInfo,
+    screen: ScreenInfo,
+    #[serde(default)]
+    keyboard_trackpad: Option<KeyboardTrackpad>,
+    #[serde(default)]
+    ports_connectivity: Option<PortsConnectivity>,
+    #[serde(default)]
+    camera: Option<CameraInfo>,
+    #[serde(default)]
+    connectivity: Option<MobileConnectivity>,
+    inspection_summary: InspectionSummary,
+    #[serde(default)]
+    blockchain: Option<BlockchainRecord>,
+    #[serde(default)]
+    price: Option<Price>,
+    /// Answers to the category's checklist, e.g.
+    /// `[{ "key": "screen_ok", "value": { "Bool": true } }]`.
+    #[serde(default)]
+    checklist: Vec<ChecklistAnswer>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ImportEntryResult {
+    index: u32,
+    external_id: String,
+    device_id: Option<u64>,
+    report_id: Option<u64>,
+    error: Option<String>,
+}
+
+fn required<T>(section: Option<T>, name: &str) -> Result<T, String> {
+    section.ok_or_else(|| format!("Missing '{}' section", name))
+}
+
+fn check_percent(value: u32, name: &str) -> Result<(), String> {
+    if value > 100 {
+        return Err(format!("{} must be between 0 and 100", name));
+    }
+    Ok(())
+}
+
+/// Validates a record and assembles the category-specific hardware section.
+fn validate_inspection_record(record: &mut InspectionRecord) -> Result<DeviceHardware, String> {
+    if record.device_id.trim().is_empty() {
+        return Err("device_id is required".to_string());
+    }
+    if record.brand.trim().is_empty() || record.model.trim().is_empty() {
+        return Err("brand and model are required".to_string());
+    }
+    if record.identity.serial_number.trim().is_empty() {
+        return Err("identity.serial_number is required".to_string());
+    }
+    if let Some(price) = &record.price {
+        price.validate()?;
+    }
+    check_percent(record.battery.health_percent, "battery.health_percent")?;
+    check_percent(record.inspection_summary.overall_score, "inspection_summary.overall_score")?;
+
+    let category = DeviceCategory::from_label(&record.category)
+        .ok_or_else(|| format!("Unsupported category '{}'", record.category))?;
+    let memory = std::mem::take(&mut record.memory);
+    match category {
+        DeviceCategory::Laptop => {
+            let memory: LaptopMemory = serde_json::from_value(memory).map_err(|e| format!("Invalid 'memory' section: {}", e))?;
+            let storage = required(record.storage.take(), "storage")?;
+            check_percent(memory.health_percent, "memory.health_percent")?;
+            check_percent(storage.health_percent, "storage.health_percent")?;
+            Ok(DeviceHardware::Laptop(LaptopHardware {
+                cpu: record.cpu.clone(),
+                gpu: required(record.gpu.take(), "gpu")?,
+                memory,
+                storage,
+                battery: record.battery.clone(),
+                screen: record.screen.clone(),
+                keyboard_trackpad: required(record.keyboard_trackpad.take(), "keyboard_trackpad")?,
+                ports_connectivity: required(record.ports_connectivity.take(), "ports_connectivity")?,
+            }))
+        },
+        DeviceCategory::Mobile | DeviceCategory::Tablet => {
+            let memory: MobileMemory = serde_json::from_value(memory).map_err(|e| format!("Invalid 'memory' section: {}", e))?;
+            check_percent(memory.health_percent, "memory.health_percent")?;
+            // Wi-Fi-only tablets have no IMEI.
+            if category == DeviceCategory::Mobile && record.identity.imei.as_deref().is_none_or(|imei| imei.trim().is_empty()) {
+                return Err("identity.imei is required for mobile devices".to_string());
+            }
+            let hardware = MobileHardware {
+                cpu: record.cpu.clone(),
+                memory,
+                battery: record.battery.clone(),
+                screen: record.screen.clone(),
+                camera: required(record.camera.take(), "camera")?,
+                connectivity: required(record.connectivity.take(), "connectivity")?,
+            };
+            Ok(if category == DeviceCategory::Mobile { DeviceHardware::Mobile(hardware) } else { DeviceHardware::Tablet(hardware) })
+        },
+    }
+}
+
+/// Creates the device for `owner` and the importer's inspection report for
+/// one record.
+fn import_inspection_record(importer: &User, owner: &User, mut record: InspectionRecord) -> Result<(u64, u64), String> {
+    let hardware = validate_inspection_record(&mut record)?;
+    let template_version = check_report_answers(hardware.category(), &record.checklist)?;
+    let specs = format!("{} {} {} ({}), {}", record.category, record.brand, record.model, record.year, record.cpu.model);
+    let notes = record.inspection_summary.recommendation.clone();
+    let device = Device {
+        id: 0,
+        user_id: owner.id,
+        name: format!("{} {}", record.brand, record.model),
+        specs,
+        brand: record.brand,
+        model: record.model,
+        year: record.year,
+        identity: record.identity,
+        hardware: Some(hardware),
+        inspection_summary: Some(record.inspection_summary),
+        blockchain: record.blockchain,
+        status: if record.price.is_some() { DeviceStatus::Listed } else { DeviceStatus::Draft },
+        price: record.price,
+    };
+    let device_id = create_device(owner, device)?;
+    let report_id = create_report(device_id, importer.name.clone(), notes, template_version, record.checklist);
+    Ok((device_id, report_id))
+}
+
+/// Imports a chunk of inspection records in the `data/devices*.json` format,
+/// either `{ "devices": [...] }` or a bare array. The caller must be an
+/// accredited specialist and signs the reports; the devices are registered
+/// to `owner_id`, the seller whose devices were inspected, so the ownership
+/// chain starts with them. Rejected entries do not stop the rest of the chunk.
+#[ic_cdk::update]
+fn import_inspection_records(json: String, owner_id: u64) -> Result<Vec<ImportEntryResult>, String> {
+    let user = caller_user()?;
+    if !user.has_role(&Role::Specialist) {
+        return Err("Only specialists can import inspection records".to_string());
+    }
+    require_accreditation(&user)?;
+    let owner = get_user(owner_id).ok_or_else(|| "Owner not found".to_string())?;
+    if !owner.has_role(&Role::Seller) || owner.suspended {
+        return Err("The owner must be an active seller".to_string());
+    }
+
+    let parsed: serde_json::Value = serde_json::from_str(&json).map_err(|e| format!("Invalid JSON: {}", e))?;
+    let entries = match parsed {
+        serde_json::Value::Array(entries) => entries,
+        serde_json::Value::Object(mut root) => match root.remove("devices") {
+            Some(serde_json::Value::Array(entries)) => entries,
+            _ => return Err("Expected a 'devices' array".to_string()),
+        },
+        _ => return Err("Expected a 'devices' array".to_string()),
+    };
+    if entries.len() > MAX_IMPORT_BATCH {
+        return Err(format!("At most {} records can be imported per call", MAX_IMPORT_BATCH));
+    }
+
+    let results = entries.into_iter().enumerate().map(|(index, entry)| {
+        let external_id = entry.get("device_id").and_then(|v| v.as_str()).unwrap_or_default().to_string();
+        let outcome = serde_json::from_value::<InspectionRecord>(entry)
+            .map_err(|e| format!("Invalid record: {}", e))
+            .and_then(|record| import_inspection_record(&user, &owner, record));
+        match outcome {
+            Ok((device_id, report_id)) => ImportEntryResult { index: index as u32, external_id, device_id: Some(device_id), report_id: Some(report_id), error: None },
+            Err(e) => ImportEntryResult { index: index as u32, external_id, device_id: None, report_id: None, error: Some(e) },
+        }
+    }).collect();
+    Ok(results)
+}
+
 /// --------- CART & ORDER APIS ----------
 #[ic_cdk::update]
-fn add_to_cart(user_id: u64, device_id: u64) -> Result<(), String> {
-    let user_opt = USERS.with(|u| u.borrow().get(&user_id).map(|v| v.clone()));
-    match user_opt {
-        Some(user) if user.role == Role::Buyer => {
-            let device_opt = DEVICES.with(|d| d.borrow().get(&device_id).map(|v| v.clone()));
-            match device_opt {
-                Some(_) => {
-                    CARTS.with(|carts| {
-                        let mut carts = carts.borrow_mut();
-                        let mut cart = carts.get(&user_id).map(|c| c.clone()).unwrap_or(Cart { user_id, device_ids: vec![] });
-                        if !cart.device_ids.contains(&device_id) {
-                            cart.device_ids.push(device_id);
-                        }
-                        carts.insert(user_id, cart);
-                    });
-                    Ok(())
-                },
-                None => Err("Device not found".to_string())
+fn add_to_cart(device_id: u64) -> Result<(), String> {
+    let user = caller_user()?;
+    if !user.has_role(&Role::Buyer) {
+        return Err("Only buyers can use the cart".to_string());
+    }
+    let user_id = user.id;
+    let device_opt = DEVICES.with(|d| d.borrow().get(&device_id).map(|v| v.clone()));
+    match device_opt {
+        Some(device) => {
+            if device.status != DeviceStatus::Listed {
+                return Err("Device is not for sale".to_string());
+            }
+            if device.user_id == user_id {
+                return Err("You cannot buy your own device".to_string());
             }
+            check_listing_policy(&device.identity)?;
+            check_not_blacklisted(&device.identity)?;
+            CARTS.with(|carts| {
+                let mut carts = carts.borrow_mut();
+                let mut cart = carts.get(&user_id).map(|c| c.clone()).unwrap_or(Cart { user_id, device_ids: vec![] });
+                if !cart.device_ids.contains(&device_id) {
+                    if cart.device_ids.len() >= settings().max_cart_items as usize {
+                        return Err("Cart is full".to_string());
+                    }
+                    cart.device_ids.push(device_id);
+                }
+                carts.insert(user_id, cart);
+                Ok(())
+            })
         },
-        _ => Err("Only buyers can use the cart".to_string())
+        None => Err("Device not found".to_string())
     }
 }
 
 #[ic_cdk::update]
-fn checkout_cart(user_id: u64) -> Result<Vec<Order>, String> {
+fn checkout_cart() -> Result<Vec<Order>, String> {
+    let user_id = caller_user()?.id;
     CARTS.with(|carts| {
         let mut carts = carts.borrow_mut();
         let cart_opt = carts.get(&user_id).map(|c| c.clone());
         match cart_opt {
             Some(cart) => {
-                let mut created_orders = Vec::new();
+                let mut reserved = Vec::new();
                 for device_id in cart.device_ids.iter() {
-                    DEVICES.with(|d| {
-                        let mut devices = d.borrow_mut();
-                        if let Some(device) = devices.remove(device_id) {
-                            let order_id = next_id(5);
-                            let now = time();
-                            let order = Order { id: order_id, buyer_id: user_id, seller_id: device.user_id, device_id: device.id, timestamp: now };
-                            ORDERS.with(|orders| orders.borrow_mut().insert(order_id, order.clone()));
-                            created_orders.push(order);
-                        }
-                    });
+                    match get_device(*device_id) {
+                        Some(device) if device.status == DeviceStatus::Listed && device.user_id != user_id => {
+                            check_listing_policy(&device.identity)?;
+                            check_not_blacklisted(&device.identity)?;
+                            reserved.push(device);
+                        },
+                        _ => return Err(format!("Device {} is no longer available; remove it from the cart", device_id)),
+                    }
+                }
+                let mut created_orders = Vec::new();
+                for mut device in reserved {
+                    let order_id = next_id(5);
+                    let now = time();
+                    let order = Order {
+                        id: order_id,
+                        buyer_id: user_id,
+                        seller_id: device.user_id,
+                        device_id: device.id,
+                        timestamp: now,
+                        price: device.price.clone(),
+                        status: OrderStatus::Pending,
+                        closed_at: None,
+                    };
+                    device.status = DeviceStatus::Reserved;
+                    DEVICES.with(|d| d.borrow_mut().insert(device.id, device));
+                    ORDERS.with(|orders| orders.borrow_mut().insert(order_id, order.clone()));
+                    index_order(&order);
+                    created_orders.push(order);
                 }
                 carts.remove(&user_id);
                 Ok(created_orders)
@@ -433,8 +3219,58 @@ fn checkout_cart(user_id: u64) -> Result<Vec<Order>, String> {
     })
 }
 
+/// The buyer confirms a pending order: the device is marked sold and its
+/// ownership moves to the buyer.
+#[ic_cdk::update]
+fn confirm_order(order_id: u64) -> Result<(), String> {
+    let user = caller_user()?;
+    let mut order = get_order(order_id).ok_or_else(|| "Order not found".to_string())?;
+    if order.buyer_id != user.id {
+        return Err("Only the buyer can confirm this order".to_string());
+    }
+    if order.status != OrderStatus::Pending {
+        return Err("Order is not pending".to_string());
+    }
+    let mut device = get_device(order.device_id).ok_or_else(|| "Device not found".to_string())?;
+    // The device may have been reported stolen while it was reserved.
+    check_not_blacklisted(&device.identity)?;
+    let now = time();
+    device.status = DeviceStatus::Sold;
+    device.user_id = order.buyer_id;
+    DEVICES.with(|d| d.borrow_mut().insert(device.id, device));
+    reindex_owner(order.device_id, order.seller_id, order.buyer_id);
+    append_ownership(order.device_id, Some(order.seller_id), order.buyer_id, OwnershipEvent::Order(order.id), now);
+    order.status = OrderStatus::Completed;
+    order.closed_at = Some(now);
+    ORDERS.with(|orders| orders.borrow_mut().insert(order_id, order));
+    Ok(())
+}
+
+/// Cancels a pending order and puts the device back on sale. Either party or
+/// an admin can cancel.
+#[ic_cdk::update]
+fn cancel_order(order_id: u64) -> Result<(), String> {
+    let user = caller_user()?;
+    let mut order = get_order(order_id).ok_or_else(|| "Order not found".to_string())?;
+    if order.buyer_id != user.id && order.seller_id != user.id {
+        require_admin()?;
+    }
+    if order.status != OrderStatus::Pending {
+        return Err("Order is not pending".to_string());
+    }
+    if let Some(mut device) = get_device(order.device_id).filter(|d| d.status == DeviceStatus::Reserved) {
+        device.status = DeviceStatus::Listed;
+        DEVICES.with(|d| d.borrow_mut().insert(device.id, device));
+    }
+    order.status = OrderStatus::Cancelled;
+    order.closed_at = Some(time());
+    ORDERS.with(|orders| orders.borrow_mut().insert(order_id, order));
+    Ok(())
+}
+
 #[ic_cdk::update]
-fn remove_from_cart(user_id: u64, device_id: u64) -> Result<(), String> {
+fn remove_from_cart(device_id: u64) -> Result<(), String> {
+    let user_id = caller_user()?.id;
     CARTS.with(|carts| {
         let mut carts = carts.borrow_mut();
         if let Some(mut cart) = carts.get(&user_id).map(|c| c.clone()) {
@@ -448,7 +3284,8 @@ fn remove_from_cart(user_id: u64, device_id: u64) -> Result<(), String> {
 }
 
 #[ic_cdk::update]
-fn clear_cart(user_id: u64) -> Result<(), String> {
+fn clear_cart() -> Result<(), String> {
+    let user_id = caller_user()?.id;
     CARTS.with(|carts| {
         let mut carts = carts.borrow_mut();
         if carts.remove(&user_id).is_some() {
@@ -460,7 +3297,8 @@ fn clear_cart(user_id: u64) -> Result<(), String> {
 }
 
 #[ic_cdk::query]
-fn get_cart(user_id: u64) -> Option<Cart> {
+fn get_cart() -> Option<Cart> {
+    let user_id = caller_user().ok()?.id;
     CARTS.with(|carts| carts.borrow().get(&user_id).map(|c| c.clone()))
 }
 
@@ -468,6 +3306,20 @@ fn get_cart(user_id: u64) -> Option<Cart> {
 fn list_orders() -> Vec<Order> {
     ORDERS.with(|orders| orders.borrow().iter().map(|(_, o)| o.clone()).collect())
 }
+
+fn get_order(order_id: u64) -> Option<Order> {
+    ORDERS.with(|orders| orders.borrow().get(&order_id).map(|o| o.clone()))
+}
+
+#[ic_cdk::query]
+fn list_buyer_orders(user_id: u64) -> Vec<Order> {
+    index_ids(&ORDERS_BY_BUYER, user_id).into_iter().filter_map(get_order).collect()
+}
+
+#[ic_cdk::query]
+fn list_seller_orders(user_id: u64) -> Vec<Order> {
+    index_ids(&ORDERS_BY_SELLER, user_id).into_iter().filter_map(get_order).collect()
+}
 #[derive(Deserialize, Debug)]
 struct AiResponse {
     response: DeviceSpec,
@@ -483,12 +3335,524 @@ struct DeviceSpec {
     must_not_have: Vec<String>,
 }
 
+/// --------- DEVICE PASSPORT ----------
+fn list_device_contracts(device_id: u64) -> Vec<WarrantyContract> {
+    index_ids(&CONTRACTS_BY_DEVICE, device_id).into_iter().filter_map(get_contract).collect()
+}
+
+fn list_device_orders(device_id: u64) -> Vec<Order> {
+    index_ids(&ORDERS_BY_DEVICE, device_id).into_iter().filter_map(get_order).collect()
+}
+
+/// The device with its inspection reports, warranty contracts, ownership
+/// chain, orders and price history in one call.
+#[ic_cdk::query]
+fn get_device_passport(device_id: u64) -> Option<DevicePassport> {
+    let device = get_device(device_id)?;
+    let DeviceListing { device, warnings } = to_listing(&listing_policy(), device);
+    Some(DevicePassport {
+        device,
+        warnings,
+        reports: list_device_reports(device_id),
+        contracts: list_device_contracts(device_id),
+        ownership: get_device_provenance(device_id),
+        orders: list_device_orders(device_id),
+        price_history: get_price_history(device_id),
+    })
+}
+
+/// --------- FULL-TEXT SEARCH ----------
+/// Source id of a device's own text in the index; report notes use the
+/// report id, which is never 0.
+const DEVICE_TEXT_SOURCE: u64 = 0;
+const DEVICE_TEXT_WEIGHT: u32 = 2;
+const REPORT_TEXT_WEIGHT: u32 = 1;
+
+/// Folds case, strips Arabic diacritics and tatweel, unifies Arabic letter
+/// variants and Latin accents, and maps Arabic-Indic digits to ASCII, so
+/// that "أيفون" matches "ايفون" and "Écran" matches "ecran".
+fn normalize_text(text: &str) -> String {
+    text.chars().flat_map(char::to_lowercase).filter_map(|c| match c {
+        '\u{064B}'..='\u{065F}' | '\u{0670}' | '\u{0640}' => None,
+        'أ' | 'إ' | 'آ' | 'ٱ' => Some('ا'),
+        'ى' | 'ئ' => Some('ي'),
+        'ؤ' => Some('و'),
+        'ة' => Some('ه'),
+        '٠'..='٩' => char::from_digit(c as u32 - '٠' as u32, 10),
+        '۰'..='۹' => char::from_digit(c as u32 - '۰' as u32, 10),
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' => Some('a'),
+        'ç' => Some('c'),
+        'è' | 'é' | 'ê' | 'ë' => Some('e'),
+        'ì' | 'í' | 'î' | 'ï' => Some('i'),
+        'ñ' => Some('n'),
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' => Some('o'),
+        'ù' | 'ú' | 'û' | 'ü' => Some('u'),
+        'ý' | 'ÿ' => Some('y'),
+        c => Some(c),
+    }).collect()
+}
+
+/// Normalized terms of `text`. Single letters are dropped; numbers are kept.
+fn tokenize(text: &str) -> Vec<String> {
+    normalize_text(text)
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|t| t.chars().count() > 1 || t.chars().all(|c| c.is_ascii_digit()))
+        .filter(|t| !t.is_empty())
+        .map(|t| t.to_string())
+        .collect()
+}
+
+/// Keys sort by term first, so all postings of a term are one range.
+fn posting_key(term: &str, device_id: u64, source_id: u64) -> String {
+    format!("{}\0{:020}\0{:020}", term, device_id, source_id)
+}
+
+fn add_postings(text: &str, device_id: u64, source_id: u64, weight: u32) {
+    TEXT_INDEX.with(|t| {
+        let mut index = t.borrow_mut();
+        for term in tokenize(text) {
+            let key = posting_key(&term, device_id, source_id);
+            let current = index.get(&key).unwrap_or(0);
+            index.insert(key, current + weight);
+        }
+    });
+}
+
+fn remove_postings(text: &str, device_id: u64, source_id: u64) {
+    TEXT_INDEX.with(|t| {
+        let mut index = t.borrow_mut();
+        for term in tokenize(text) {
+            index.remove(&posting_key(&term, device_id, source_id));
+        }
+    });
+}
+
+fn device_text(device: &Device) -> String {
+    format!("{} {} {} {}", device.name, device.specs, device.brand, device.model)
+}
+
+fn index_device_text(device: &Device) {
+    add_postings(&device_text(device), device.id, DEVICE_TEXT_SOURCE, DEVICE_TEXT_WEIGHT);
+}
+
+fn index_report_text(report: &Report) {
+    add_postings(&report.notes, report.device_id, report.id, REPORT_TEXT_WEIGHT);
+}
+
+/// Ranks devices whose name, specs or report notes contain the query terms.
+/// Devices in any status are returned; look them up to check availability.
+#[ic_cdk::query]
+fn search_text(query: String, limit: u32) -> Vec<TextMatch> {
+    let mut terms = tokenize(&query);
+    terms.sort();
+    terms.dedup();
+    let mut hits: std::collections::BTreeMap<u64, (u32, u32)> = std::collections::BTreeMap::new();
+    TEXT_INDEX.with(|t| {
+        let index = t.borrow();
+        for term in &terms {
+            let prefix = format!("{}\0", term);
+            let mut matched = std::collections::BTreeSet::new();
+            for (key, weight) in index.range(prefix.clone()..).take_while(|(k, _)| k.starts_with(&prefix)) {
+                let device_id = key[prefix.len()..prefix.len() + 20].parse::<u64>().unwrap_or_default();
+                let hit = hits.entry(device_id).or_insert((0, 0));
+                hit.1 += weight;
+                if matched.insert(device_id) {
+                    hit.0 += 1;
+                }
+            }
+        }
+    });
+    let mut results: Vec<TextMatch> = hits.into_iter()
+        .map(|(device_id, (matched_terms, score))| TextMatch { device_id, matched_terms, score })
+        .collect();
+    results.sort_by(|a, b| (b.matched_terms, b.score).cmp(&(a.matched_terms, a.score)).then(a.device_id.cmp(&b.device_id)));
+    results.truncate(limit.min(MAX_PAGE_SIZE) as usize);
+    results
+}
+
+/// --------- DEVICE CERTIFICATE APIS ----------
+/// Derivation path of the key that signs device certificates.
+fn certificate_derivation_path() -> Vec<Vec<u8>> {
+    vec![b"device_certificates".to_vec()]
+}
+
+fn certificate_key_id(key_name: &str) -> EcdsaKeyId {
+    EcdsaKeyId { curve: EcdsaCurve::Secp256k1, name: key_name.to_string() }
+}
+
+fn certificate_document(payload: &CertificatePayload) -> Vec<u8> {
+    serde_json::to_vec(payload).unwrap()
+}
+
+fn cached_public_key(key_name: &str) -> Option<Vec<u8>> {
+    CERTIFICATE_KEYS.with(|k| k.borrow().get(&key_name.to_string()))
+}
+
+/// Fetches the public key of `key_name` from the management canister unless
+/// it is already cached.
+async fn ensure_public_key(key_name: &str) -> Result<Vec<u8>, String> {
+    if let Some(public_key) = cached_public_key(key_name) {
+        return Ok(public_key);
+    }
+    let args = EcdsaPublicKeyArgs {
+        canister_id: None,
+        derivation_path: certificate_derivation_path(),
+        key_id: certificate_key_id(key_name),
+    };
+    let public_key = ecdsa_public_key(&args).await
+        .map_err(|e| format!("Failed to fetch the certificate public key: {:?}", e))?
+        .public_key;
+    CERTIFICATE_KEYS.with(|k| k.borrow_mut().insert(key_name.to_string(), public_key.clone()));
+    Ok(public_key)
+}
+
+/// What a certificate for the device would attest right now: its identity,
+/// current owner and latest inspection report.
+fn certificate_payload(device_id: u64) -> Result<CertificatePayload, String> {
+    let device = get_device(device_id).ok_or_else(|| "Device not found".to_string())?;
+    check_not_blacklisted(&device.identity)?;
+    let report = index_ids(&REPORTS_BY_DEVICE, device_id).last().copied().and_then(get_report)
+        .ok_or_else(|| "The device has no inspection report to certify".to_string())?;
+    let owner = get_user(device.user_id).ok_or_else(|| "Owner not found".to_string())?;
+    Ok(CertificatePayload {
+        canister_id: ic_cdk::api::canister_self(),
+        device_id,
+        imei: device.identity.imei,
+        issued_at: time(),
+        owner_id: owner.id,
+        owner_principal: owner.principal,
+        report_hash: report.content_hash,
+        report_id: report.id,
+        serial_number: device.identity.serial_number,
+    })
+}
+
+/// Whether two payloads attest the same facts, whenever they were issued.
+fn same_attestation(a: &CertificatePayload, b: &CertificatePayload) -> bool {
+    CertificatePayload { issued_at: 0, ..a.clone() } == CertificatePayload { issued_at: 0, ..b.clone() }
+}
+
+/// Signs a certificate binding the device's identity and owner to its latest
+/// inspection report. Every signature costs the canister cycles, so only
+/// accredited specialists and admins can issue, and a device whose latest
+/// certificate still attests the same facts gets that certificate back.
+#[ic_cdk::update]
+async fn issue_certificate(device_id: u64) -> Result<DeviceCertificate, String> {
+    if require_admin().is_err() {
+        let user = caller_user()?;
+        if !user.has_role(&Role::Specialist) || require_accreditation(&user).is_err() {
+            return Err("Only accredited specialists and admins can issue certificates".to_string());
+        }
+    }
+    let payload = certificate_payload(device_id)?;
+    let key_name = settings().ecdsa_key_name;
+    let latest = index_ids(&CERTIFICATES_BY_DEVICE, device_id).last().copied().and_then(get_certificate);
+    if let Some(latest) = latest.filter(|c| c.key_name == key_name && same_attestation(&c.payload, &payload)) {
+        return Ok(latest);
+    }
+    let digest: Hash = Sha256::digest(certificate_document(&payload)).into();
+
+    ensure_public_key(&key_name).await?;
+    let args = SignWithEcdsaArgs {
+        message_hash: digest.to_vec(),
+        derivation_path: certificate_derivation_path(),
+        key_id: certificate_key_id(&key_name),
+    };
+    let signature = sign_with_ecdsa(&args).await
+        .map_err(|e| format!("Failed to sign the certificate: {:?}", e))?
+        .signature;
+
+    // The device may have been transferred, reported or re-inspected while
+    // the signature was pending; never store a certificate for stale facts.
+    if !same_attestation(&payload, &certificate_payload(device_id)?) {
+        return Err("The device changed while the certificate was being signed; issue it again".to_string());
+    }
+    let id = next_id(10);
+    let certificate = DeviceCertificate { id, payload, key_name, signature };
+    CERTIFICATES.with(|c| c.borrow_mut().insert(id, certificate.clone()));
+    index_insert(&CERTIFICATES_BY_DEVICE, device_id, id);
+    certify(certificate_tree_key(id), digest);
+    Ok(certificate)
+}
+
+/// Public key for the current signing key, once it has signed a certificate
+/// or been loaded with `load_certificate_public_key`.
+#[ic_cdk::query]
+fn get_certificate_public_key() -> Option<CertificatePublicKey> {
+    let key_name = settings().ecdsa_key_name;
+    cached_public_key(&key_name).map(|public_key| CertificatePublicKey { key_name, public_key })
+}
+
+#[ic_cdk::update]
+async fn load_certificate_public_key() -> Result<CertificatePublicKey, String> {
+    require_admin()?;
+    let key_name = settings().ecdsa_key_name;
+    let public_key = ensure_public_key(&key_name).await?;
+    Ok(CertificatePublicKey { key_name, public_key })
+}
+
+#[ic_cdk::query]
+fn get_certificate(certificate_id: u64) -> Option<DeviceCertificate> {
+    CERTIFICATES.with(|c| c.borrow().get(&certificate_id).map(|v| v.clone()))
+}
+
+#[ic_cdk::query]
+fn list_device_certificates(device_id: u64) -> Vec<DeviceCertificate> {
+    index_ids(&CERTIFICATES_BY_DEVICE, device_id).into_iter().filter_map(get_certificate).collect()
+}
+
+/// The certificate with a certificate and witness for its document hash.
+/// Only works as a query call.
+#[ic_cdk::query]
+fn get_certified_certificate(certificate_id: u64) -> Result<CertifiedDeviceCertificate, String> {
+    let device_certificate = get_certificate(certificate_id).ok_or_else(|| "Certificate not found".to_string())?;
+    let certificate = ic_cdk::api::data_certificate()
+        .ok_or_else(|| "Certificates are only available in query calls".to_string())?;
+    Ok(CertifiedDeviceCertificate {
+        document: String::from_utf8(certificate_document(&device_certificate.payload)).unwrap(),
+        witness: certified_witness(&certificate_tree_key(certificate_id)),
+        certificate,
+        device_certificate,
+    })
+}
+
+/// The certificate as a JSON document for QR codes and files.
+#[ic_cdk::query]
+fn export_certificate(certificate_id: u64) -> Option<String> {
+    let certificate = get_certificate(certificate_id)?;
+    let export = CertificateExport {
+        format: "origintrace-certificate-v1",
+        certificate_id,
+        key_name: &certificate.key_name,
+        document: String::from_utf8(certificate_document(&certificate.payload)).unwrap(),
+        signature: hex::encode(&certificate.signature),
+        public_key: cached_public_key(&certificate.key_name).map(hex::encode),
+    };
+    serde_json::to_string(&export).ok()
+}
+
+/// --------- DEVICE COMPARISON APIS ----------
+/// An attribute compared by `compare_devices` and how to read it from the
+/// stored inspection data.
+struct ComparedAttribute {
+    name: &'static str,
+    higher_is_better: bool,
+    read: fn(&Device) -> Option<u64>,
+}
+
+const COMPARED_ATTRIBUTES: &[ComparedAttribute] = &[
+    ComparedAttribute { name: "cpu_benchmark_score", higher_is_better: true, read: |d| d.cpu().map(|c| c.benchmark_score as u64) },
+    ComparedAttribute { name: "battery_health_percent", higher_is_better: true, read: |d| d.battery().map(|b| b.health_percent as u64) },
+    ComparedAttribute { name: "storage_health_percent", higher_is_better: true, read: |d| match &d.hardware {
+        Some(DeviceHardware::Laptop(h)) => Some(h.storage.health_percent as u64),
+        Some(DeviceHardware::Mobile(h) | DeviceHardware::Tablet(h)) => Some(h.memory.health_percent as u64),
+        None => None,
+    } },
+    ComparedAttribute { name: "screen_dead_pixels", higher_is_better: false, read: |d| d.screen().map(|s| s.dead_pixels as u64) },
+    ComparedAttribute { name: "overall_score", higher_is_better: true, read: |d| d.inspection_summary.as_ref().map(|s| s.overall_score as u64) },
+    ComparedAttribute { name: "price_usd_cents", higher_is_better: false, read: |d| d.price_usd_cents() },
+];
+
+/// The device holding the strictly best value; ties and rows where fewer
+/// than two devices have data have no winner.
+fn row_winner(device_ids: &[u64], values: &[Option<u64>], higher_is_better: bool) -> Option<u64> {
+    let present: Vec<(u64, u64)> = device_ids.iter().zip(values).filter_map(|(id, v)| v.map(|v| (*id, v))).collect();
+    if present.len() < 2 {
+        return None;
+    }
+    let best = if higher_is_better { present.iter().map(|(_, v)| *v).max()? } else { present.iter().map(|(_, v)| *v).min()? };
+    let mut leaders = present.iter().filter(|(_, v)| *v == best);
+    match (leaders.next(), leaders.next()) {
+        (Some((id, _)), None) => Some(*id),
+        _ => None,
+    }
+}
+
+fn format_value(attribute: &str, value: u64) -> String {
+    if attribute == "price_usd_cents" {
+        format!("${}.{:02}", value / 100, value % 100)
+    } else {
+        value.to_string()
+    }
+}
+
+/// Compares two to four devices on their inspection data and price.
+#[ic_cdk::query]
+fn compare_devices(device_ids: Vec<u64>) -> Result<DeviceComparison, String> {
+    if device_ids.len() < 2 || device_ids.len() > MAX_COMPARED_DEVICES {
+        return Err(format!("Compare between 2 and {} devices", MAX_COMPARED_DEVICES));
+    }
+    let mut seen = std::collections::BTreeSet::new();
+    if !device_ids.iter().all(|id| seen.insert(*id)) {
+        return Err("Each device can only be compared once".to_string());
+    }
+    let devices = device_ids.iter()
+        .map(|id| get_device(*id).ok_or_else(|| format!("Device {} not found", id)))
+        .collect::<Result<Vec<Device>, String>>()?;
+
+    let mut rows = Vec::new();
+    let mut summary = Vec::new();
+    let mut wins: std::collections::BTreeMap<u64, usize> = std::collections::BTreeMap::new();
+    for ComparedAttribute { name: attribute, higher_is_better, read } in COMPARED_ATTRIBUTES {
+        let values: Vec<Option<u64>> = devices.iter().map(read).collect();
+        let winner = row_winner(&device_ids, &values, *higher_is_better);
+        match winner {
+            Some(winner) => {
+                *wins.entry(winner).or_insert(0) += 1;
+                let listed: Vec<String> = devices.iter().zip(&values)
+                    .map(|(d, v)| format!("{} {}", d.name, v.map(|v| format_value(attribute, v)).unwrap_or_else(|| "n/a".to_string())))
+                    .collect();
+                let name = devices.iter().find(|d| d.id == winner).map(|d| d.name.as_str()).unwrap_or_default();
+                summary.push(format!("{}: {} is best ({})", attribute, name, listed.join(" vs ")));
+            },
+            None if values.iter().filter(|v| v.is_some()).count() < 2 => {
+                summary.push(format!("{}: not enough inspection data to compare", attribute));
+            },
+            None => summary.push(format!("{}: tied", attribute)),
+        }
+        rows.push(ComparisonRow { attribute: attribute.to_string(), higher_is_better: *higher_is_better, values, winner });
+    }
+    if let Some((leader, count)) = wins.iter().max_by_key(|(id, count)| (**count, std::cmp::Reverse(**id))) {
+        let name = devices.iter().find(|d| d.id == *leader).map(|d| d.name.as_str()).unwrap_or_default();
+        summary.insert(0, format!("{} (device {}) wins {} of {} attributes", name, leader, count, rows.len()));
+    }
+    Ok(DeviceComparison { device_ids, rows, summary })
+}
+
+/// --------- PAGINATED LIST APIS ----------
+/// Sort key of a record: `(price in US cents, id)` for price orders and
+/// `(id, id)` otherwise. Amounts in different currencies are not comparable,
+/// so only US-dollar prices are ranked.
+fn sort_key(sort: SortOrder, id: u64, usd_cents: Option<u64>) -> (u64, u64) {
+    match sort {
+        SortOrder::Newest | SortOrder::Oldest => (id, id),
+        SortOrder::PriceLowToHigh => (usd_cents.unwrap_or(u64::MAX), id),
+        // Unpriced records sort below every real price, i.e. last.
+        SortOrder::PriceHighToLow => (usd_cents.unwrap_or(0), id),
+    }
+}
+
+fn parse_cursor(cursor: &str) -> Result<(u64, u64), String> {
+    let (primary, id) = cursor.split_once(':').ok_or_else(|| "Invalid cursor".to_string())?;
+    match (primary.parse(), id.parse()) {
+        (Ok(primary), Ok(id)) => Ok((primary, id)),
+        _ => Err("Invalid cursor".to_string()),
+    }
+}
+
+fn page_limit(request: &PageRequest) -> Result<usize, String> {
+    if request.limit == 0 {
+        return Err("limit must be at least 1".to_string());
+    }
+    Ok(request.limit.min(MAX_PAGE_SIZE) as usize)
+}
+
+fn is_id_order(sort: SortOrder) -> bool {
+    matches!(sort, SortOrder::Newest | SortOrder::Oldest)
+}
+
+/// Orders `keys` as requested and returns the ids of one page plus the
+/// cursor of the record that starts the next page.
+fn page_ids(mut keys: Vec<(u64, u64)>, request: &PageRequest) -> Result<(Vec<u64>, Option<String>), String> {
+    let limit = page_limit(request)?;
+    let descending = matches!(request.sort, SortOrder::Newest | SortOrder::PriceHighToLow);
+    keys.sort();
+    if descending {
+        keys.reverse();
+    }
+    let start = match request.cursor.as_deref() {
+        Some(cursor) => {
+            let cursor = parse_cursor(cursor)?;
+            keys.iter().position(|key| if descending { *key <= cursor } else { *key >= cursor }).unwrap_or(keys.len())
+        },
+        None => 0,
+    };
+    let page: Vec<(u64, u64)> = keys.iter().skip(start).take(limit + 1).cloned().collect();
+    let next_cursor = page.get(limit).map(|(primary, id)| format!("{}:{}", primary, id));
+    Ok((page.into_iter().take(limit).map(|(_, id)| id).collect(), next_cursor))
+}
+
+/// One page of `map` in id order, for `Newest` and `Oldest`. Reads the map
+/// from the cursor and stops once the page is full, keeping only the records
+/// `keep` accepts.
+fn page_in_id_order<V: Storable>(map: &StableBTreeMap<u64, V, Memory>, request: &PageRequest, keep: impl Fn(&V) -> bool) -> Result<(Vec<V>, Option<String>), String> {
+    use std::ops::Bound;
+    let limit = page_limit(request)?;
+    let cursor = match request.cursor.as_deref() {
+        Some(cursor) => Bound::Included(parse_cursor(cursor)?.1),
+        None => Bound::Unbounded,
+    };
+    let records: Box<dyn Iterator<Item = (u64, V)> + '_> = if request.sort == SortOrder::Newest {
+        Box::new(map.range((Bound::Unbounded, cursor)).rev())
+    } else {
+        Box::new(map.range((cursor, Bound::Unbounded)))
+    };
+    let mut page: Vec<(u64, V)> = records.filter(|(_, v)| keep(v)).take(limit + 1).collect();
+    let next_cursor = if page.len() > limit { page.pop().map(|(id, _)| format!("{}:{}", id, id)) } else { None };
+    Ok((page.into_iter().map(|(_, v)| v).collect(), next_cursor))
+}
+
+/// A page of the devices for sale.
+#[ic_cdk::query]
+fn list_devices_page(request: PageRequest) -> Result<DevicePage, String> {
+    search_devices(DeviceFilter::default(), request)
+}
+
+/// A page of the devices for sale that match `filter`.
+#[ic_cdk::query]
+fn search_devices(filter: DeviceFilter, request: PageRequest) -> Result<DevicePage, String> {
+    let policy = listing_policy();
+    let (devices, next_cursor) = if is_id_order(request.sort) {
+        DEVICES.with(|devices| page_in_id_order(&devices.borrow(), &request, |d| is_for_sale(&policy, d) && filter.matches(d)))?
+    } else {
+        let keys = DEVICES.with(|devices| devices.borrow().iter()
+            .filter(|(_, d)| is_for_sale(&policy, d) && filter.matches(d))
+            .map(|(id, d)| sort_key(request.sort, id, d.price_usd_cents()))
+            .collect());
+        let (ids, next_cursor) = page_ids(keys, &request)?;
+        (ids.into_iter().filter_map(get_device).collect(), next_cursor)
+    };
+    let items = devices.into_iter().map(|d| to_listing(&policy, d)).collect();
+    Ok(DevicePage { items, next_cursor })
+}
+
+#[ic_cdk::query]
+fn list_reports_page(request: PageRequest) -> Result<ReportPage, String> {
+    if !is_id_order(request.sort) {
+        return Err("Reports cannot be sorted by price".to_string());
+    }
+    let (items, next_cursor) = REPORTS.with(|reports| page_in_id_order(&reports.borrow(), &request, |_| true))?;
+    Ok(ReportPage { items, next_cursor })
+}
+
+#[ic_cdk::query]
+fn list_contracts_page(request: PageRequest) -> Result<ContractPage, String> {
+    if !is_id_order(request.sort) {
+        return Err("Contracts cannot be sorted by price".to_string());
+    }
+    let (items, next_cursor) = CONTRACTS.with(|contracts| page_in_id_order(&contracts.borrow(), &request, |_| true))?;
+    Ok(ContractPage { items, next_cursor })
+}
+
+#[ic_cdk::query]
+fn list_orders_page(request: PageRequest) -> Result<OrderPage, String> {
+    if is_id_order(request.sort) {
+        let (items, next_cursor) = ORDERS.with(|orders| page_in_id_order(&orders.borrow(), &request, |_| true))?;
+        return Ok(OrderPage { items, next_cursor });
+    }
+    let keys = ORDERS.with(|orders| orders.borrow().iter()
+        .map(|(id, o)| sort_key(request.sort, id, o.price.as_ref().and_then(Price::usd_cents)))
+        .collect());
+    let (ids, next_cursor) = page_ids(keys, &request)?;
+    Ok(OrderPage { items: ids.into_iter().filter_map(get_order).collect(), next_cursor })
+}
+
 /// --------- AI CALL & DEVICE CHECK ----------
 #[ic_cdk::update]
 async fn call_model(prompt: String) -> Result<Vec<Device>, String> {
     let body_json = json!({ "user_message": prompt });
     let request = HttpRequestArgs {
-        url: "https://rawan7-icp-ai-agent-api2.hf.space/generate".to_string(),
+        url: settings().ai_endpoint_url,
         method: HttpMethod::POST,
         headers: vec![HttpHeader {
             name: "Content-Type".to_string(),
@@ -538,21 +3902,29 @@ thread_local! {
 // ========================
 fn check_device_in_storage(spec: DeviceSpec) -> Vec<Device> {
     let mut matched_devices = Vec::new();
+    let filter = DeviceFilter {
+        category: DeviceCategory::from_label(&spec.device_type),
+        max_price: Some(spec.budget_usd as u64 * 100),
+        ..DeviceFilter::default()
+    };
 
+    let policy = listing_policy();
     DEVICES.with(|devices| {
-        for (_, device) in devices.borrow().iter() {
-            if device.specs.contains(&spec.device_type) && device.price_usd() <= spec.budget_usd {
+        for (_, device) in devices.borrow().iter().filter(|(_, d)| is_for_sale(&policy, d) && filter.matches(d)) {
+            let text = device.match_text();
+            let type_matches = filter.category.is_some() || text.contains(&normalize_text(&spec.device_type));
+            if type_matches {
                 let mut matches_constraints = true;
 
                 for constraint in &spec.hard_constraints {
-                    if !device.specs.contains(constraint) {
+                    if !text.contains(&normalize_text(constraint)) {
                         matches_constraints = false;
                         break;
                     }
                 }
 
                 for forbidden in &spec.must_not_have {
-                    if device.specs.contains(forbidden) {
+                    if text.contains(&normalize_text(forbidden)) {
                         matches_constraints = false;
                         break;
                     }
@@ -582,7 +3954,7 @@ async fn process_user_query(user_text: String) -> Result<String, String> {
     });
 
     let request = HttpRequestArgs {
-        url: "https://rawan7-icp-ai-agent-api2.hf.space/generate".to_string(),
+        url: settings().ai_endpoint_url,
         method: HttpMethod::POST,
         headers: vec![HttpHeader {
             name: "Content-Type".to_string(),
@@ -614,8 +3986,10 @@ async fn explain_device_part(device_id: u64, part_name: String) -> Result<String
         None => return Err("Device not found".to_string()),
     };
 
-    // 2. البحث عن الجزئية المطلوبة في specs
-    let specs_text = if device.specs.to_lowercase().contains(&part_name.to_lowercase()) {
+    // 2. البحث عن الجزئية المطلوبة في الأقسام ثم في specs
+    let specs_text = if let Some(section) = device.section_json(&part_name) {
+        format!("{}: {}", part_name, section)
+    } else if device.specs.to_lowercase().contains(&part_name.to_lowercase()) {
         device.specs.clone()
     } else {
         format!("No specific info about '{}'. Full specs: {}", part_name, device.specs)
@@ -632,3 +4006,180 @@ async fn explain_device_part(device_id: u64, part_name: String) -> Result<String
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn imei_check_digit() {
+        assert!(is_valid_imei("490154203237518"));
+        assert!(is_valid_imei("356789012345672"));
+        assert!(!is_valid_imei("490154203237517"));
+        assert!(!is_valid_imei("49015420323751"));
+        assert!(!is_valid_imei("4901542032375180"));
+        assert!(!is_valid_imei("49015420323751a"));
+        assert!(!is_valid_imei("49015420 237518"));
+    }
+
+    #[test]
+    fn imei_normalization() {
+        assert_eq!(normalize_imei("49-015420-323751-8"), "490154203237518");
+        assert_eq!(normalize_imei(" 4901 5420 3237 518 "), "490154203237518");
+        assert!(is_valid_imei(&normalize_imei("49-015420-323751-8")));
+        assert_eq!(imei_key("49 0154 2032 3751 8"), imei_key("490154203237518"));
+    }
+
+    #[test]
+    fn id_order_pages_follow_the_cursor() {
+        let memory = MemoryManager::init(DefaultMemoryImpl::default()).get(MemoryId::new(0));
+        let mut map: StableBTreeMap<u64, u64, Memory> = StableBTreeMap::init(memory);
+        for id in 1..=7 {
+            map.insert(id, id * 10);
+        }
+        let request = |sort, cursor: Option<&str>| PageRequest { cursor: cursor.map(str::to_string), limit: 3, sort };
+
+        let (items, cursor) = page_in_id_order(&map, &request(SortOrder::Oldest, None), |_| true).unwrap();
+        assert_eq!((items, cursor.as_deref()), (vec![10, 20, 30], Some("4:4")));
+        let (items, cursor) = page_in_id_order(&map, &request(SortOrder::Oldest, Some("4:4")), |v| *v != 50).unwrap();
+        assert_eq!((items, cursor), (vec![40, 60, 70], None));
+
+        let (items, cursor) = page_in_id_order(&map, &request(SortOrder::Newest, None), |_| true).unwrap();
+        assert_eq!((items, cursor.as_deref()), (vec![70, 60, 50], Some("4:4")));
+        let (items, cursor) = page_in_id_order(&map, &request(SortOrder::Newest, Some("4:4")), |_| true).unwrap();
+        assert_eq!((items, cursor.as_deref()), (vec![40, 30, 20], Some("1:1")));
+
+        assert!(page_in_id_order(&map, &request(SortOrder::Newest, Some("x")), |_| true).is_err());
+    }
+
+    #[test]
+    fn price_sort_ranks_only_dollar_prices() {
+        let usd = Price { amount: 50_000, currency: "USD".to_string() };
+        let yen = Price { amount: 100_000, currency: "JPY".to_string() };
+        assert_eq!(sort_key(SortOrder::PriceLowToHigh, 1, usd.usd_cents()), (50_000, 1));
+        assert_eq!(sort_key(SortOrder::PriceLowToHigh, 2, yen.usd_cents()), (u64::MAX, 2));
+        assert_eq!(sort_key(SortOrder::PriceHighToLow, 2, yen.usd_cents()), (0, 2));
+    }
+
+    #[test]
+    fn sample_imeis_are_valid() {
+        let data: serde_json::Value = serde_json::from_str(include_str!("../data/devices2.json")).unwrap();
+        for device in data["devices"].as_array().unwrap() {
+            let imei = device["identity"]["imei"].as_str().unwrap();
+            assert!(is_valid_imei(&normalize_imei(imei)), "sample IMEI {} fails the check digit", imei);
+        }
+    }
+
+    #[test]
+    fn answers_follow_the_checklist() {
+        let item = |key: &str, value_type, required| ChecklistItem { key: key.to_string(), label: key.to_string(), value_type, required };
+        let template = ChecklistTemplate {
+            category: DeviceCategory::Mobile,
+            version: 1,
+            items: vec![
+                item("screen", ChecklistValueType::Choice(vec!["ok".to_string(), "cracked".to_string()]), true),
+                item("battery", ChecklistValueType::Number, true),
+                item("notes", ChecklistValueType::Text, false),
+            ],
+            updated_by: Principal::anonymous(),
+            updated_at: 0,
+        };
+        let answer = |key: &str, value| ChecklistAnswer { key: key.to_string(), value };
+        let screen = answer("screen", ChecklistValue::Choice("ok".to_string()));
+        let battery = answer("battery", ChecklistValue::Number(87.0));
+
+        assert_eq!(validate_answers(&template, &[screen.clone(), battery.clone()]), Ok(()));
+        assert_eq!(
+            validate_answers(&template, &[screen.clone(), battery.clone(), answer("camera", ChecklistValue::Bool(true))]),
+            Err("'camera' is not on the checklist".to_string())
+        );
+        assert_eq!(
+            validate_answers(&template, &[screen.clone(), battery.clone(), battery.clone()]),
+            Err("'battery' is answered more than once".to_string())
+        );
+        assert_eq!(
+            validate_answers(&template, &[screen.clone(), answer("battery", ChecklistValue::Text("87".to_string()))]),
+            Err("Invalid value for 'battery'".to_string())
+        );
+        assert_eq!(
+            validate_answers(&template, &[screen.clone(), answer("battery", ChecklistValue::Number(f64::NAN))]),
+            Err("Invalid value for 'battery'".to_string())
+        );
+        assert_eq!(
+            validate_answers(&template, &[answer("screen", ChecklistValue::Choice("scratched".to_string())), battery.clone()]),
+            Err("Invalid value for 'screen'".to_string())
+        );
+        assert_eq!(
+            validate_answers(&template, &[answer("notes", ChecklistValue::Text("boxed".to_string()))]),
+            Err("Missing required checks: screen, battery".to_string())
+        );
+    }
+
+    #[test]
+    fn arabic_and_latin_text_is_normalized() {
+        assert_eq!(normalize_text("أيفون"), normalize_text("ايفون"));
+        assert_eq!(normalize_text("إصلاح آيباد"), "اصلاح ايباد");
+        assert_eq!(normalize_text("شاشةٌ"), "شاشه");
+        assert_eq!(normalize_text("مـــمـتـاز"), "ممتاز");
+        assert_eq!(normalize_text("مستشفى"), "مستشفي");
+        assert_eq!(normalize_text("Écran Cassé"), "ecran casse");
+        assert_eq!(normalize_text("١٢٨ جيجا ۲۵۶"), "128 جيجا 256");
+    }
+
+    #[test]
+    fn tokens_drop_single_letters_but_keep_numbers() {
+        assert_eq!(tokenize("iPhone X 8 GB, a Écran!"), vec!["iphone", "8", "gb", "ecran"]);
+        assert_eq!(tokenize("ذاكرة ٨ جيجا و شاشة"), vec!["ذاكره", "8", "جيجا", "شاشه"]);
+    }
+
+    fn sample_report(template_version: Option<u32>, answers: Vec<ChecklistAnswer>) -> ReportV2 {
+        ReportV2 { id: 5, device_id: 3, specialist_name: "Lab".to_string(), notes: "Battery replaced".to_string(), timestamp: 1, template_version, answers }
+    }
+
+    #[test]
+    fn canonical_report_bytes_are_stable() {
+        // The offline verifier hashes these exact bytes; its own tests use the
+        // free-form document below.
+        let report = Report::from(sample_report(None, vec![]));
+        assert_eq!(
+            String::from_utf8(canonical_report(&report)).unwrap(),
+            r#"{"answers":[],"device_id":3,"id":5,"notes":"Battery replaced","specialist_name":"Lab","template_version":null,"timestamp":1}"#
+        );
+        assert_eq!(report.content_hash, "2bffcdd747c955e1a666c54e2f429159f787b5d0f69b0ec30a2275d61480dd6a");
+
+        let answers = vec![
+            ChecklistAnswer { key: "screen".to_string(), value: ChecklistValue::Choice("ok".to_string()) },
+            ChecklistAnswer { key: "battery".to_string(), value: ChecklistValue::Number(87.0) },
+        ];
+        let report = Report::from(sample_report(Some(2), answers));
+        assert_eq!(
+            String::from_utf8(canonical_report(&report)).unwrap(),
+            r#"{"answers":[{"key":"screen","value":{"Choice":"ok"}},{"key":"battery","value":{"Number":87.0}}],"device_id":3,"id":5,"notes":"Battery replaced","specialist_name":"Lab","template_version":2,"timestamp":1}"#
+        );
+        assert_eq!(report.content_hash, "0adbefde505061dff2085259d787813ee416d16baf245f8c1b11e552dbad3bcb");
+    }
+
+    #[test]
+    fn migrated_reports_hash_like_new_ones() {
+        // A version 2 record as stored, field by field.
+        let stored = bincode::serialize(&(5u64, 3u64, "Lab", "Battery replaced", 1u64, None::<u32>, Vec::<ChecklistAnswer>::new())).unwrap();
+        let migrated = Report::upgrade(2, &stored).unwrap();
+        let mut fresh = Report { content_hash: String::new(), ..migrated.clone() };
+        fresh.content_hash = sha256_hex(&canonical_report(&fresh));
+        assert_eq!(migrated.content_hash, fresh.content_hash);
+        assert_eq!(migrated.content_hash, "2bffcdd747c955e1a666c54e2f429159f787b5d0f69b0ec30a2275d61480dd6a");
+    }
+
+    #[test]
+    fn comparison_rows_have_a_single_winner() {
+        let ids = [11, 12, 13];
+        assert_eq!(row_winner(&ids, &[Some(4), Some(9), Some(7)], true), Some(12));
+        assert_eq!(row_winner(&ids, &[Some(4), Some(9), Some(7)], false), Some(11));
+        // Dead pixels and price: fewer is better, missing values do not win.
+        assert_eq!(row_winner(&ids, &[Some(3), None, Some(0)], false), Some(13));
+        assert_eq!(row_winner(&ids, &[Some(9), Some(9), Some(7)], true), None);
+        assert_eq!(row_winner(&ids, &[Some(0), Some(2), Some(0)], false), None);
+        assert_eq!(row_winner(&ids, &[Some(9), Some(9), Some(12)], true), Some(13));
+        assert_eq!(row_winner(&ids, &[None, Some(5), None], true), None);
+        assert_eq!(row_winner(&ids, &[None, None, None], false), None);
+    }
+}
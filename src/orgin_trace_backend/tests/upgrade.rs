@@ -0,0 +1,260 @@
+// Runs against the compiled canister in a PocketIC server, so these tests are
+// ignored by a plain `cargo test`. CI runs them with:
+//   cargo build --target wasm32-unknown-unknown --release -p orgin_trace_backend
+//   POCKET_IC_BIN=/path/to/pocket-ic cargo test -p orgin_trace_backend --test upgrade -- --ignored
+// The wasm path can be overridden with ORIGIN_TRACE_BACKEND_WASM.
+//
+// `legacy_records_survive_upgrade` also needs the wasm of the first release,
+// which stored raw bincode records. Build it from the repository's first
+// commit and copy it to target/legacy/orgin_trace_backend.wasm, or point
+// ORIGIN_TRACE_LEGACY_WASM at it.
+
+use candid::{decode_one, encode_args, encode_one, CandidType, Deserialize, Principal};
+use pocket_ic::PocketIc;
+
+#[derive(CandidType)]
+enum Role {
+    Seller,
+}
+
+#[derive(CandidType, Default)]
+struct DeviceIdentity {
+    serial_number: String,
+    icloud_frp_lock: bool,
+    carrier_lock: bool,
+    bios_lock: bool,
+    tamper_flags: Vec<String>,
+}
+
+#[derive(CandidType)]
+struct Price {
+    amount: u64,
+    currency: String,
+}
+
+#[derive(CandidType)]
+struct DeviceInput {
+    name: String,
+    specs: String,
+    brand: String,
+    model: String,
+    year: u32,
+    identity: DeviceIdentity,
+    price: Price,
+}
+
+#[derive(CandidType, Deserialize)]
+struct Device {
+    id: u64,
+    user_id: u64,
+    name: String,
+    specs: String,
+}
+
+#[derive(CandidType, Deserialize)]
+struct User {
+    id: u64,
+    principal: Principal,
+    name: String,
+}
+
+#[derive(CandidType, Deserialize)]
+struct Report {
+    id: u64,
+    device_id: u64,
+    notes: String,
+    content_hash: String,
+}
+
+#[derive(CandidType, Deserialize)]
+struct WarrantyContract {
+    id: u64,
+    seller_id: u64,
+    buyer_id: u64,
+    device_id: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
+enum OrderStatus {
+    Pending,
+    Completed,
+    Cancelled,
+}
+
+#[derive(CandidType, Deserialize)]
+struct Order {
+    id: u64,
+    buyer_id: u64,
+    seller_id: u64,
+    device_id: u64,
+    status: OrderStatus,
+}
+
+#[derive(CandidType, Deserialize)]
+struct DeviceListing {
+    device: Device,
+}
+
+fn read_wasm(variable: &str, default: &str) -> Vec<u8> {
+    let path = std::env::var(variable)
+        .unwrap_or_else(|_| format!("{}/../../target/{}", env!("CARGO_MANIFEST_DIR"), default));
+    std::fs::read(&path).unwrap_or_else(|e| panic!("cannot read canister wasm at {}: {}", path, e))
+}
+
+fn backend_wasm() -> Vec<u8> {
+    read_wasm("ORIGIN_TRACE_BACKEND_WASM", "wasm32-unknown-unknown/release/orgin_trace_backend.wasm")
+}
+
+fn legacy_wasm() -> Vec<u8> {
+    read_wasm("ORIGIN_TRACE_LEGACY_WASM", "legacy/orgin_trace_backend.wasm")
+}
+
+fn update<T: for<'a> Deserialize<'a> + CandidType>(pic: &PocketIc, canister: Principal, sender: Principal, method: &str, arg: Vec<u8>) -> T {
+    let reply = pic
+        .update_call(canister, sender, method, arg)
+        .unwrap_or_else(|e| panic!("{} was rejected: {:?}", method, e));
+    decode_one(&reply).unwrap()
+}
+
+fn query<T: for<'a> Deserialize<'a> + CandidType>(pic: &PocketIc, canister: Principal, method: &str, arg: Vec<u8>) -> T {
+    let reply = pic
+        .query_call(canister, Principal::anonymous(), method, arg)
+        .unwrap_or_else(|e| panic!("{} was rejected: {:?}", method, e));
+    decode_one(&reply).unwrap()
+}
+
+fn add_device(pic: &PocketIc, canister: Principal, seller: Principal, name: &str) -> u64 {
+    let input = DeviceInput {
+        name: name.to_string(),
+        specs: "specs".to_string(),
+        brand: "Dell".to_string(),
+        model: "XPS 13".to_string(),
+        year: 2022,
+        identity: DeviceIdentity { serial_number: format!("SN-{}", name), ..Default::default() },
+        price: Price { amount: 45_000, currency: "USD".to_string() },
+    };
+    let reply = pic
+        .update_call(canister, seller, "add_device", encode_one(input).unwrap())
+        .expect("add_device was rejected");
+    decode_one::<Result<u64, String>>(&reply).unwrap().expect("add_device failed")
+}
+
+#[test]
+#[ignore = "needs the release wasm and a PocketIC server"]
+fn ids_are_not_reused_after_upgrade() {
+    let wasm = backend_wasm();
+    let pic = PocketIc::new();
+    let canister = pic.create_canister();
+    pic.add_cycles(canister, 2_000_000_000_000);
+    pic.install_canister(canister, wasm.clone(), vec![], None);
+
+    let seller = Principal::from_slice(&[1, 2, 3, 4]);
+    let reply = pic
+        .update_call(canister, seller, "add_user", encode_args(("Seller".to_string(), "seller@example.com".to_string(), Role::Seller)).unwrap())
+        .expect("add_user was rejected");
+    decode_one::<Result<u64, String>>(&reply).unwrap().expect("add_user failed");
+
+    let first = add_device(&pic, canister, seller, "before upgrade");
+    pic.upgrade_canister(canister, wasm, vec![], None).expect("upgrade failed");
+    let second = add_device(&pic, canister, seller, "after upgrade");
+    assert!(second > first, "device id {} was reused after upgrade", second);
+
+    let reply = pic
+        .query_call(canister, Principal::anonymous(), "get_device", encode_one(first).unwrap())
+        .expect("get_device was rejected");
+    let device = decode_one::<Option<Device>>(&reply).unwrap().expect("device lost after upgrade");
+    assert_eq!(device.id, first);
+    assert_eq!(device.name, "before upgrade");
+}
+
+/// The legacy canister identified users by a caller-supplied id, so every
+/// call below is made anonymously with that id as the first argument.
+#[derive(CandidType)]
+enum LegacyRole {
+    Seller,
+    Specialist,
+    Buyer,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LegacyOrder {
+    id: u64,
+}
+
+#[test]
+#[ignore = "needs the release and legacy wasms and a PocketIC server"]
+fn legacy_records_survive_upgrade() {
+    let pic = PocketIc::new();
+    let admin = Principal::from_slice(&[9, 9, 9]);
+    let canister = pic.create_canister_with_settings(Some(admin), None);
+    pic.add_cycles(canister, 2_000_000_000_000);
+    pic.install_canister(canister, legacy_wasm(), vec![], Some(admin));
+
+    let anyone = Principal::anonymous();
+    let legacy_user = |name: &str, role: LegacyRole| -> u64 {
+        update(&pic, canister, anyone, "add_user", encode_args((name.to_string(), format!("{}@example.com", name), role)).unwrap())
+    };
+    let seller = legacy_user("seller", LegacyRole::Seller);
+    let specialist = legacy_user("specialist", LegacyRole::Specialist);
+    let buyer = legacy_user("buyer", LegacyRole::Buyer);
+    let legacy_device = |name: &str| -> u64 {
+        let reply: Result<u64, String> = update(&pic, canister, anyone, "add_device", encode_args((seller, name.to_string(), "16GB RAM".to_string())).unwrap());
+        reply.expect("legacy add_device failed")
+    };
+    let kept = legacy_device("kept laptop");
+    let sold = legacy_device("sold phone");
+    let report: Result<u64, String> = update(&pic, canister, anyone, "add_report", encode_args((specialist, kept, "Specialist".to_string(), "Battery replaced".to_string())).unwrap());
+    let report = report.expect("legacy add_report failed");
+    let contract: Result<u64, String> = update(&pic, canister, anyone, "create_contract", encode_args((seller, buyer, kept, 6u32)).unwrap());
+    let contract = contract.expect("legacy create_contract failed");
+    let added: Result<(), String> = update(&pic, canister, anyone, "add_to_cart", encode_args((buyer, sold)).unwrap());
+    added.expect("legacy add_to_cart failed");
+    let orders: Result<Vec<LegacyOrder>, String> = update(&pic, canister, anyone, "checkout_cart", encode_one(buyer).unwrap());
+    let order_id = orders.expect("legacy checkout_cart failed")[0].id;
+
+    pic.upgrade_canister(canister, backend_wasm(), vec![], Some(admin)).expect("upgrade failed");
+
+    let user: Option<User> = query(&pic, canister, "get_user", encode_one(seller).unwrap());
+    let user = user.expect("seller lost after upgrade");
+    assert_eq!((user.id, user.name.as_str(), user.principal), (seller, "seller", Principal::anonymous()));
+
+    let device: Option<Device> = query(&pic, canister, "get_device", encode_one(kept).unwrap());
+    let device = device.expect("device lost after upgrade");
+    assert_eq!((device.id, device.user_id, device.name.as_str(), device.specs.as_str()), (kept, seller, "kept laptop", "16GB RAM"));
+
+    let stored: Option<Report> = query(&pic, canister, "get_report", encode_one(report).unwrap());
+    let stored = stored.expect("report lost after upgrade");
+    assert_eq!((stored.id, stored.device_id, stored.notes.as_str()), (report, kept, "Battery replaced"));
+    assert_eq!(stored.content_hash.len(), 64);
+
+    let stored: Option<WarrantyContract> = query(&pic, canister, "get_contract", encode_one(contract).unwrap());
+    let stored = stored.expect("contract lost after upgrade");
+    assert_eq!((stored.id, stored.seller_id, stored.buyer_id, stored.device_id), (contract, seller, buyer, kept));
+
+    let orders: Vec<Order> = query(&pic, canister, "list_buyer_orders", encode_one(buyer).unwrap());
+    let order = orders.into_iter().find(|o| o.id == order_id).expect("order lost after upgrade");
+    assert_eq!((order.id, order.buyer_id, order.seller_id, order.device_id), (order_id, buyer, seller, sold));
+    assert_eq!(order.status, OrderStatus::Completed);
+
+    let listed: Vec<DeviceListing> = query(&pic, canister, "list_user_devices", encode_one(seller).unwrap());
+    assert_eq!(listed.iter().map(|l| l.device.id).collect::<Vec<_>>(), vec![kept]);
+
+    // Migrated users can only act again once an admin links their principal.
+    let seller_principal = Principal::from_slice(&[1, 1, 1]);
+    let current: Option<User> = update(&pic, canister, seller_principal, "get_current_user", vec![]);
+    assert!(current.is_none());
+    let linked: Result<(), String> = update(&pic, canister, admin, "link_user_principal", encode_args((seller, seller_principal)).unwrap());
+    linked.expect("link_user_principal failed");
+    let current: Option<User> = update(&pic, canister, seller_principal, "get_current_user", vec![]);
+    assert_eq!(current.map(|u| u.id), Some(seller));
+    let renamed: Result<(), String> = update(&pic, canister, seller_principal, "update_user", encode_args(("renamed".to_string(), "seller@example.com".to_string())).unwrap());
+    renamed.expect("linked seller cannot manage their profile");
+
+    // The counters were rebuilt from the legacy records, so new ids continue past them.
+    let newcomer = Principal::from_slice(&[2, 2, 2]);
+    let user: Result<u64, String> = update(&pic, canister, newcomer, "add_user", encode_args(("newcomer".to_string(), "newcomer@example.com".to_string(), Role::Seller)).unwrap());
+    let user = user.expect("add_user failed after upgrade");
+    assert!(user > seller.max(specialist).max(buyer), "user id {} was reused after upgrade", user);
+    let device = add_device(&pic, canister, newcomer, "after legacy upgrade");
+    assert!(device > kept.max(sold), "device id {} was reused after upgrade", device);
+}
@@ -0,0 +1,338 @@
+//! Offline verifier for OriginTrace device certificates.
+//!
+//! Checks a certificate exported by the backend's `export_certificate` (the
+//! JSON carried by the certificate QR code) without calling the canister:
+//!
+//! ```text
+//! origin-trace-verify --certificate cert.json --public-key <hex> [--report report.json]
+//! ```
+//!
+//! `--public-key` is the hex SEC1 key returned by `get_certificate_public_key`
+//! and published by the operator. `--report` is the report document returned
+//! by `get_report_document`. Use `-` to read the certificate from stdin.
+//! The exit code is 0 when no check fails, 1 when one does and 2 on bad usage.
+
+use k256::ecdsa::signature::hazmat::PrehashVerifier;
+use k256::ecdsa::{Signature, VerifyingKey};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::process::ExitCode;
+
+const FORMAT: &str = "origintrace-certificate-v1";
+
+const USAGE: &str = "usage: origin-trace-verify --certificate <file|-> --public-key <hex> [--report <file>]";
+
+/// The JSON produced by `export_certificate`.
+#[derive(Deserialize)]
+struct CertificateExport {
+    format: String,
+    certificate_id: u64,
+    key_name: String,
+    /// The exact bytes that were signed.
+    document: String,
+    signature: String,
+    public_key: Option<String>,
+}
+
+/// The signed payload; principals arrive as text.
+#[derive(Deserialize)]
+struct CertificatePayload {
+    canister_id: String,
+    device_id: u64,
+    imei: Option<String>,
+    issued_at: u64,
+    owner_id: u64,
+    owner_principal: String,
+    report_hash: String,
+    report_id: u64,
+    serial_number: String,
+}
+
+/// The fields of a report document the verifier cross-checks.
+#[derive(Deserialize)]
+struct ReportHeader {
+    id: u64,
+    device_id: u64,
+}
+
+enum Outcome {
+    Pass(String),
+    Fail(String),
+    Skip(String),
+}
+
+struct Args {
+    certificate: String,
+    public_key: String,
+    report: Option<String>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut certificate = None;
+    let mut public_key = None;
+    let mut report = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let slot = match arg.as_str() {
+            "--certificate" => &mut certificate,
+            "--public-key" => &mut public_key,
+            "--report" => &mut report,
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            other => return Err(format!("unknown argument '{}'\n{}", other, USAGE)),
+        };
+        *slot = Some(args.next().ok_or_else(|| format!("{} needs a value\n{}", arg, USAGE))?);
+    }
+    Ok(Args {
+        certificate: certificate.ok_or_else(|| format!("--certificate is required\n{}", USAGE))?,
+        public_key: public_key.ok_or_else(|| format!("--public-key is required\n{}", USAGE))?,
+        report,
+    })
+}
+
+fn read_input(path: &str) -> Result<Vec<u8>, String> {
+    if path == "-" {
+        let mut bytes = Vec::new();
+        std::io::stdin().read_to_end(&mut bytes).map_err(|e| format!("cannot read stdin: {}", e))?;
+        Ok(bytes)
+    } else {
+        std::fs::read(path).map_err(|e| format!("cannot read {}: {}", path, e))
+    }
+}
+
+/// Formats IC nanoseconds since the epoch as a UTC date and time.
+fn format_timestamp(nanos: u64) -> String {
+    let secs = nanos / 1_000_000_000;
+    let days = (secs / 86_400) as i64;
+    let (hour, minute, second) = (secs % 86_400 / 3600, secs % 3600 / 60, secs % 60);
+    // Civil-from-days (Howard Hinnant).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, hour, minute, second)
+}
+
+fn check_public_key(export: &CertificateExport, supplied: &str) -> Outcome {
+    match &export.public_key {
+        Some(embedded) if embedded.eq_ignore_ascii_case(supplied) => Outcome::Pass("matches the key named in the certificate".to_string()),
+        Some(_) => Outcome::Fail("the certificate names a different key than the published one".to_string()),
+        None => Outcome::Skip("the certificate does not name a key".to_string()),
+    }
+}
+
+fn check_signature(export: &CertificateExport, public_key: &str) -> Outcome {
+    let key = match hex::decode(public_key.trim()).ok().and_then(|bytes| VerifyingKey::from_sec1_bytes(&bytes).ok()) {
+        Some(key) => key,
+        None => return Outcome::Fail("the public key is not a hex SEC1 secp256k1 key".to_string()),
+    };
+    let signature = match hex::decode(export.signature.trim()).ok().and_then(|bytes| Signature::from_slice(&bytes).ok()) {
+        Some(signature) => signature,
+        None => return Outcome::Fail("the signature is not a 64-byte hex ECDSA signature".to_string()),
+    };
+    // Threshold ECDSA does not promise low-S signatures; k256 only accepts those.
+    let signature = signature.normalize_s().unwrap_or(signature);
+    let digest = Sha256::digest(export.document.as_bytes());
+    match key.verify_prehash(&digest, &signature) {
+        Ok(()) => Outcome::Pass(format!("valid for key '{}'", export.key_name)),
+        Err(_) => Outcome::Fail("the signature does not match the certificate and public key".to_string()),
+    }
+}
+
+fn check_report(payload: &CertificatePayload, report_path: Option<&str>) -> Outcome {
+    let path = match report_path {
+        Some(path) => path,
+        None => return Outcome::Skip("no report file given (--report)".to_string()),
+    };
+    let bytes = match read_input(path) {
+        Ok(bytes) => bytes,
+        Err(e) => return Outcome::Fail(e),
+    };
+    let hash = hex::encode(Sha256::digest(&bytes));
+    if !hash.eq_ignore_ascii_case(&payload.report_hash) {
+        return Outcome::Fail(format!("the file hashes to {}, the certificate expects {}", hash, payload.report_hash));
+    }
+    match serde_json::from_slice::<ReportHeader>(&bytes) {
+        Ok(report) if report.id != payload.report_id || report.device_id != payload.device_id => Outcome::Fail(format!(
+            "the file is report {} for device {}, the certificate covers report {} for device {}",
+            report.id, report.device_id, payload.report_id, payload.device_id
+        )),
+        _ => Outcome::Pass(format!("matches report {}", payload.report_id)),
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::from(2);
+        },
+    };
+    let export = match read_input(&args.certificate)
+        .and_then(|bytes| serde_json::from_slice::<CertificateExport>(&bytes).map_err(|e| format!("not an OriginTrace certificate: {}", e)))
+    {
+        Ok(export) => export,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::from(2);
+        },
+    };
+
+    let mut checks: Vec<(&str, Outcome)> = Vec::new();
+    checks.push(("format", if export.format == FORMAT {
+        Outcome::Pass(FORMAT.to_string())
+    } else {
+        Outcome::Fail(format!("unsupported format '{}'", export.format))
+    }));
+    let payload = serde_json::from_str::<CertificatePayload>(&export.document);
+    checks.push(("payload", match &payload {
+        Ok(_) => Outcome::Pass("readable".to_string()),
+        Err(e) => Outcome::Fail(format!("cannot be read: {}", e)),
+    }));
+    checks.push(("public key", check_public_key(&export, &args.public_key)));
+    checks.push(("signature", check_signature(&export, &args.public_key)));
+    checks.push(("report hash", match &payload {
+        Ok(payload) => check_report(payload, args.report.as_deref()),
+        Err(_) => Outcome::Skip("the payload could not be read".to_string()),
+    }));
+
+    println!("OriginTrace certificate #{}", export.certificate_id);
+    if let Ok(payload) = &payload {
+        let imei = payload.imei.as_deref().map(|imei| format!(", IMEI {}", imei)).unwrap_or_default();
+        println!("  device:  {} (serial {}{})", payload.device_id, payload.serial_number, imei);
+        println!("  owner:   user {} ({})", payload.owner_id, payload.owner_principal);
+        println!("  report:  #{} {}", payload.report_id, payload.report_hash);
+        println!("  issued:  {} by canister {}", format_timestamp(payload.issued_at), payload.canister_id);
+    }
+    println!();
+
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    for (name, outcome) in &checks {
+        let (label, detail) = match outcome {
+            Outcome::Pass(detail) => { passed += 1; ("PASS", detail) },
+            Outcome::Fail(detail) => { failed += 1; ("FAIL", detail) },
+            Outcome::Skip(detail) => { skipped += 1; ("SKIP", detail) },
+        };
+        println!("  {}  {:<12} {}", label, name, detail);
+    }
+    println!();
+    let verdict = if failed == 0 { "PASS" } else { "FAIL" };
+    println!("Result: {} ({} passed, {} failed, {} skipped)", verdict, passed, failed, skipped);
+    if failed == 0 { ExitCode::SUCCESS } else { ExitCode::FAILURE }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k256::ecdsa::signature::hazmat::PrehashSigner;
+    use k256::ecdsa::SigningKey;
+
+    /// The backend's canonical document for this report, pinned by its
+    /// `canonical_report_bytes_are_stable` test.
+    const REPORT: &str = r#"{"answers":[],"device_id":3,"id":5,"notes":"Battery replaced","specialist_name":"Lab","template_version":null,"timestamp":1}"#;
+
+    fn signing_key() -> SigningKey {
+        SigningKey::from_slice(&[7u8; 32]).unwrap()
+    }
+
+    fn document(report_id: u64) -> String {
+        format!(
+            r#"{{"canister_id":"aaaaa-aa","device_id":3,"imei":"356789012345672","issued_at":1760000000000000000,"owner_id":2,"owner_principal":"2vxsx-fae","report_hash":"{}","report_id":{},"serial_number":"SAMA13SN001"}}"#,
+            hex::encode(Sha256::digest(REPORT.as_bytes())),
+            report_id
+        )
+    }
+
+    fn export(document: String, signature: Signature) -> CertificateExport {
+        CertificateExport {
+            format: FORMAT.to_string(),
+            certificate_id: 1,
+            key_name: "dfx_test_key".to_string(),
+            document,
+            signature: hex::encode(signature.to_bytes()),
+            public_key: None,
+        }
+    }
+
+    fn sign(document: &str) -> Signature {
+        signing_key().sign_prehash(&Sha256::digest(document.as_bytes())).unwrap()
+    }
+
+    fn public_key() -> String {
+        hex::encode(signing_key().verifying_key().to_sec1_bytes())
+    }
+
+    fn report_file(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("origin-trace-verify-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn signature_round_trip() {
+        let document = document(5);
+        let payload: CertificatePayload = serde_json::from_str(&document).unwrap();
+        assert_eq!((payload.device_id, payload.report_id), (3, 5));
+        let signature = sign(&document);
+        assert!(matches!(check_signature(&export(document, signature), &public_key()), Outcome::Pass(_)));
+    }
+
+    #[test]
+    fn high_s_signature_is_accepted() {
+        let document = document(5);
+        let signature = sign(&document);
+        let (r, s) = signature.split_scalars();
+        let high = Signature::from_scalars(r, -s).unwrap();
+        assert!(high.normalize_s().is_some(), "test signature should be high-S");
+        assert!(matches!(check_signature(&export(document, high), &public_key()), Outcome::Pass(_)));
+    }
+
+    #[test]
+    fn tampered_document_fails() {
+        let document = document(5);
+        let signature = sign(&document);
+        let tampered = document.replace(r#""owner_id":2"#, r#""owner_id":9"#);
+        assert_ne!(tampered, document);
+        assert!(matches!(check_signature(&export(tampered, signature), &public_key()), Outcome::Fail(_)));
+    }
+
+    #[test]
+    fn wrong_public_key_fails() {
+        let document = document(5);
+        let export = export(document.clone(), sign(&document));
+        let other = hex::encode(SigningKey::from_slice(&[8u8; 32]).unwrap().verifying_key().to_sec1_bytes());
+        assert!(matches!(check_signature(&export, &other), Outcome::Fail(_)));
+        assert!(matches!(check_signature(&export, "zz"), Outcome::Fail(_)));
+    }
+
+    #[test]
+    fn report_is_checked_by_hash_and_id() {
+        let path = report_file("report.json", REPORT);
+        let matching: CertificatePayload = serde_json::from_str(&document(5)).unwrap();
+        assert!(matches!(check_report(&matching, Some(&path)), Outcome::Pass(_)));
+
+        // Same bytes, so the hash matches, but the certificate names another report.
+        let wrong_id: CertificatePayload = serde_json::from_str(&document(6)).unwrap();
+        assert!(matches!(check_report(&wrong_id, Some(&path)), Outcome::Fail(_)));
+
+        let edited = report_file("edited.json", &REPORT.replace("Battery replaced", "Like new"));
+        assert!(matches!(check_report(&matching, Some(&edited)), Outcome::Fail(_)));
+        assert!(matches!(check_report(&matching, None), Outcome::Skip(_)));
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(edited).unwrap();
+    }
+
+    #[test]
+    fn timestamps_are_formatted_in_utc() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(951_782_400_000_000_000), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_timestamp(1_760_000_000_123_456_789), "2025-10-09 08:53:20 UTC");
+        assert_eq!(format_timestamp(1_735_689_599_000_000_000), "2024-12-31 23:59:59 UTC");
+    }
+}